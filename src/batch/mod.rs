@@ -0,0 +1,353 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::path::Path;
+use rand;
+use rayon::prelude::*;
+use image;
+
+use error;
+use error::{Error, Result};
+use render;
+use scene::Scene;
+use sheet;
+use sheet::Tile;
+
+// One swept parameter and every value it takes, kept as text so the
+// values go into file names exactly as they were written
+pub struct Sweep {
+    pub param: String,
+    pub values: Vec<String>
+}
+
+const PARAMS: [&str; 5] = ["seed", "frequency", "colormap", "dt", "phase"];
+
+// Parses `param=values`, where the values are a comma-separated list
+// and/or inclusive ranges `lo..hi` or `lo..hi:step` (the step defaults
+// to 1), e.g. `seed=1..8`, `dt=0.001..0.004:0.001` or `colormap=0,3`
+pub fn parse_sweep(spec: &str) -> Result<Sweep> {
+    let mut parts = spec.splitn(2, '=');
+    let param = parts.next().unwrap_or("").trim();
+    let values = match parts.next() {
+        Some(values) => values,
+        None => return error::invalid(format!(
+            "Sweeps are written param=values, not {}", spec))
+    };
+
+    if !PARAMS.contains(&param) {
+        return error::invalid(format!(
+            "Unknown sweep parameter {} (expected one of {})",
+            param, PARAMS.join(", ")));
+    }
+
+    let mut to_r = Vec::new();
+    for item in values.split(',') {
+        let item = item.trim();
+
+        if !item.contains("..") {
+            to_r.push(String::from(item));
+            continue;
+        }
+
+        let (range, step) = match item.find(':') {
+            Some(idx) => (&item[..idx], &item[idx + 1..]),
+            None => (item, "1")
+        };
+
+        // `contains("..")` above means both ends are there
+        let mut ends = range.splitn(2, "..");
+        let lo = ends.next().unwrap_or("");
+        let hi = ends.next().unwrap_or("");
+        let num = |v: &str| v.trim().parse::<f64>()
+            .map_err(|_| Error::Invalid(format!("{} is not a number in {}",
+                                                v, spec)));
+
+        let (lo_v, hi_v, step_v) = (num(lo)?, num(hi)?, num(step)?);
+        if !lo_v.is_finite() || !hi_v.is_finite() || !step_v.is_finite() {
+            return error::invalid("Sweep ranges must be finite numbers");
+        }
+        if step_v <= 0.0 {
+            return error::invalid("Sweep steps must be positive");
+        }
+        if hi_v < lo_v {
+            return error::invalid("Sweep ranges must go upwards");
+        }
+
+        // integer ranges stay integers, so seeds and frequencies print
+        // (and parse) cleanly
+        let integral = !lo.contains('.') && !hi.contains('.')
+            && !step.contains('.');
+        let count = ((hi_v - lo_v) / step_v + 1e-9).floor() as usize + 1;
+
+        for i in 0..count {
+            let v = lo_v + i as f64 * step_v;
+            to_r.push(if integral {
+                format!("{}", v as i64)
+            } else {
+                format!("{}", (v * 1e9).round() / 1e9)
+            });
+        }
+    }
+
+    // the same value twice would render the same image twice, under
+    // names that clash
+    for (i, v) in to_r.iter().enumerate() {
+        if to_r[..i].contains(v) {
+            return error::invalid(format!("{} repeats in {}", v, spec));
+        }
+    }
+
+    return Ok(Sweep { param: String::from(param), values: to_r });
+}
+
+fn _apply(scene: &mut Scene, param: &str, value: &str) -> Result<()> {
+    let bad = |what: &str| Error::Invalid(
+        format!("{} is not a valid {} for a sweep", value, what));
+
+    match param {
+        "seed" => scene.seed = Some(value.parse()
+                                    .map_err(|_| bad("seed"))?),
+        "frequency" => scene.noise.frequency = value.parse()
+            .map_err(|_| bad("frequency"))?,
+        "colormap" => scene.color.function = value.parse()
+            .map_err(|_| bad("color function"))?,
+        "dt" => scene.integrator.dt = value.parse()
+            .map_err(|_| bad("time step"))?,
+        "phase" => scene.field.phase = Some(value.parse()
+                                            .map_err(|_| bad("phase"))?),
+        _ => unreachable!()
+    }
+
+    return Ok(());
+}
+
+// Fills `{index}` and `{param}` placeholders in an output name. A name
+// without any placeholders gets the index appended, so renders never
+// overwrite each other.
+fn _file_name(template: &str, index: usize,
+              values: &[(&str, &str)]) -> String {
+    let mut to_r = template.replace("{index}", &format!("{:03}", index));
+    for &(param, value) in values {
+        to_r = to_r.replace(&format!("{{{}}}", param), value);
+    }
+
+    if to_r == template {
+        to_r = format!("{}-{:03}", template, index);
+    }
+
+    return to_r;
+}
+
+// The current value of a sweepable parameter, for captions
+fn _value(scene: &Scene, param: &str) -> String {
+    return match param {
+        "seed" => scene.seed.map(|s| s.to_string())
+            .unwrap_or_else(|| String::from("random")),
+        "frequency" => scene.noise.frequency.to_string(),
+        "colormap" => scene.color.function.to_string(),
+        "dt" => scene.integrator.dt.to_string(),
+        "phase" => scene.field.phase.map(|p| p.to_string())
+            .unwrap_or_else(|| String::from("random")),
+        _ => unreachable!()
+    };
+}
+
+// Every combination of the swept values, applied to copies of `base`
+// and paired with a caption naming the values, with the first sweep
+// varying slowest
+pub fn expand(base: &Scene,
+              sweeps: &[Sweep]) -> Result<Vec<(Scene, Vec<String>)>> {
+    let mut combos: Vec<Vec<(&str, &str)>> = vec![Vec::new()];
+    for sweep in sweeps {
+        let mut next = Vec::new();
+        for combo in &combos {
+            for value in &sweep.values {
+                let mut c = combo.clone();
+                c.push((sweep.param.as_str(), value.as_str()));
+                next.push(c);
+            }
+        }
+        combos = next;
+    }
+
+    let mut base = base.clone();
+    base.output.dump_config = false;
+
+    // unless it is swept, every render shares one seed, so the other
+    // parameters can be compared on the same noise
+    if base.seed.is_none() {
+        base.seed = Some(u64::from(rand::random::<u32>()));
+    }
+
+    let mut to_r = Vec::with_capacity(combos.len());
+    for (index, combo) in combos.iter().enumerate() {
+        let mut scene = base.clone();
+        for &(param, value) in combo {
+            _apply(&mut scene, param, value)?;
+        }
+        scene.output.name = _file_name(&base.output.name, index, combo);
+
+        // check every scene up front, so a bad value fails the batch
+        // before anything is written
+        render::validate(&scene)?;
+        if to_r.iter().any(|(s, _): &(Scene, Vec<String>)| {
+            s.output.name == scene.output.name
+        }) {
+            return error::invalid(format!(
+                "Two renders would be written to {}; add {{index}} or the \
+                 other swept parameters to the output name",
+                scene.output.name));
+        }
+
+        let caption = combo.iter()
+            .map(|&(param, value)| format!("{}={}", param, value))
+            .collect();
+        to_r.push((scene, caption));
+    }
+
+    return Ok(to_r);
+}
+
+// Renders every scene in parallel, then lays them out in a labeled
+// contact sheet with `cols` renders of `thumb_width` pixels per row
+pub fn run(jobs: &[(Scene, Vec<String>)], sweeps: &[Sweep],
+           index_name: &str, cols: usize, thumb_width: u32) -> Result<()> {
+    println!("Rendering {} images...", jobs.len());
+
+    // every render runs, even if one fails, and the first failure (in
+    // job order) is reported
+    let tiles: Vec<Result<Tile>> = jobs.par_iter()
+        .map(|(scene, caption)| {
+            let img = match render::render(scene)? {
+                Some(img) => img,
+                None => return error::invalid(
+                    "Batch renders must be still images")
+            };
+            println!("Wrote {}", render::output_path(scene));
+
+            // shrink right away rather than holding every full render
+            let th = (thumb_width as f64 * img.height() as f64
+                      / img.width() as f64).round().max(1.0) as u32;
+            Ok(Tile {
+                image: image::imageops::resize(&img, thumb_width, th,
+                                               image::FilterType::Triangle),
+                caption: caption.clone()
+            })
+        })
+        .collect();
+    let tiles = tiles.into_iter().collect::<Result<Vec<Tile>>>()?;
+
+    // the title lists whatever the sweeps left fixed
+    let first = &jobs[0].0;
+    let title: Vec<String> = PARAMS.iter()
+        .filter(|&&p| !sweeps.iter().any(|s| s.param == p))
+        .map(|&p| format!("{}={}", p, _value(first, p)))
+        .collect();
+    let title = format!("{} {}", first.field.kind, title.join(" "));
+
+    let sheet = sheet::contact_sheet(&tiles, cols, thumb_width,
+                                     Some(&title))?;
+
+    let names: Vec<String> = jobs.iter()
+        .map(|(s, _)| render::output_path(s))
+        .collect();
+    let meta = vec![(String::from("Software"),
+                     format!("perlin {}", env!("CARGO_PKG_VERSION"))),
+                    (String::from("renders"), names.join("\n"))];
+
+    return render::save_image(image::ImageRgb8(sheet),
+                              Path::new(&format!("{}.png", index_name)),
+                              &meta);
+}
+
+#[cfg(test)]
+mod tests {
+    use scene::Scene;
+    use super::{parse_sweep, expand, _file_name};
+
+    #[test]
+    fn sweeps_mix_ranges_and_lists() {
+        let s = parse_sweep("seed=1..3,7").unwrap();
+        assert_eq!(s.param, "seed");
+        assert_eq!(s.values, vec!["1", "2", "3", "7"]);
+
+        let s = parse_sweep("dt=0.001..0.004:0.001").unwrap();
+        assert_eq!(s.values, vec!["0.001", "0.002", "0.003", "0.004"]);
+
+        let s = parse_sweep("frequency=2..8:3").unwrap();
+        assert_eq!(s.values, vec!["2", "5", "8"]);
+    }
+
+    #[test]
+    fn bad_sweeps_are_errors() {
+        for spec in &["seed", "size=1..3", "seed=3..1", "seed=1..3:0",
+                      "dt=0..nan", "dt=0..1:-1", "seed=a..b",
+                      "seed=1,1", "seed=1..3,2"] {
+            assert!(parse_sweep(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn expand_takes_the_cartesian_product() {
+        let mut base = Scene::default();
+        base.output.name = String::from("s{seed}-c{colormap}");
+        let sweeps = vec![parse_sweep("seed=1,2").unwrap(),
+                          parse_sweep("colormap=0..2").unwrap()];
+
+        let jobs = expand(&base, &sweeps).unwrap();
+        assert_eq!(jobs.len(), 6);
+
+        // the last sweep varies fastest
+        let (ref scene, ref caption) = jobs[4];
+        assert_eq!(scene.seed, Some(2));
+        assert_eq!(scene.color.function, 1);
+        assert_eq!(scene.output.name, "s2-c1");
+        assert_eq!(caption, &vec![String::from("seed=2"),
+                                  String::from("colormap=1")]);
+    }
+
+    #[test]
+    fn expand_rejects_bad_values_and_clashing_names() {
+        // caught here, before any of the renders would start
+        let sweeps = vec![parse_sweep("colormap=0,9").unwrap()];
+        assert!(expand(&Scene::default(), &sweeps).is_err());
+
+        let mut base = Scene::default();
+        base.output.name = String::from("s{seed}");
+        let sweeps = vec![parse_sweep("seed=1,2").unwrap(),
+                          parse_sweep("frequency=2,3").unwrap()];
+        assert!(expand(&base, &sweeps).is_err());
+    }
+
+    #[test]
+    fn unswept_seeds_are_shared() {
+        let sweeps = vec![parse_sweep("frequency=2,4").unwrap()];
+        let jobs = expand(&Scene::default(), &sweeps).unwrap();
+        assert!(jobs[0].0.seed.is_some());
+        assert_eq!(jobs[0].0.seed, jobs[1].0.seed);
+    }
+
+    #[test]
+    fn names_without_placeholders_get_the_index() {
+        assert_eq!(_file_name("out", 3, &[("seed", "9")]), "out-003");
+        assert_eq!(_file_name("run-{index}", 12, &[]), "run-012");
+        assert_eq!(_file_name("f{frequency}", 0, &[("frequency", "4")]),
+                   "f4");
+    }
+}
@@ -0,0 +1,112 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+pub type Rgb = (f64, f64, f64);
+
+#[derive(Clone, Copy)]
+pub enum Blend {
+    // colors add up, so busy areas saturate towards white
+    Additive,
+
+    // each pixel shows the mean color of the strokes through it,
+    // with brightness taken from the density
+    Average
+}
+
+// Maps a normalized flux value onto display brightness. The default
+// exposure and gamma give the original square-root curve.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Tone {
+    pub exposure: f64,
+    pub gamma: f64
+}
+
+impl Default for Tone {
+    fn default() -> Tone {
+        return Tone { exposure: 1.0, gamma: 0.5 };
+    }
+}
+
+impl Tone {
+    pub fn apply(&self, v: f64) -> f64 {
+        return (v * self.exposure).min(1.0).powf(self.gamma);
+    }
+}
+
+// Parses "#rrggbb" (the "#" is optional) into channels in [0, 1]
+pub fn parse_hex(s: &str) -> Option<Rgb> {
+    let hex = s.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16)
+        .ok()
+        .map(|c| f64::from(c) / 255.0);
+
+    return match (channel(0), channel(2), channel(4)) {
+        (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+        _ => None
+    };
+}
+
+// h, s and v in [0, 1]
+pub fn hsv_to_rgb(h: f64, s: f64, v: f64) -> Rgb {
+    let h = (h - h.floor()) * 6.0;
+    let sector = h.floor();
+    let f = h - sector;
+
+    let p = v * (1.0 - s);
+    let q = v * (1.0 - s * f);
+    let t = v * (1.0 - s * (1.0 - f));
+
+    return match sector as u8 {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q)
+    };
+}
+
+// Turns an accumulated color sum and the matching (unnormalized) flux
+// into a displayable color with channels in [0, 1]
+pub fn blend(color_sum: Rgb, flux: f64, max_flux: f64,
+             mode: Blend, tone: &Tone) -> Rgb {
+    if flux <= 0.0 || max_flux <= 0.0 {
+        return (0.0, 0.0, 0.0);
+    }
+
+    let (r, g, b) = color_sum;
+
+    return match mode {
+        Blend::Additive => {
+            (tone.apply(r / max_flux),
+             tone.apply(g / max_flux),
+             tone.apply(b / max_flux))
+        },
+
+        Blend::Average => {
+            let bright = tone.apply(flux / max_flux);
+            (r / flux * bright, g / flux * bright, b / flux * bright)
+        }
+    };
+}
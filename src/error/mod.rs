@@ -0,0 +1,88 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use image;
+
+// Everything that can stop a render. Each variant carries a message
+// saying what we were doing, so the CLI can print something actionable.
+#[derive(Debug)]
+pub enum Error {
+    // reading or writing a file
+    Io(String, io::Error),
+
+    // decoding or encoding an image
+    Image(String, image::ImageError),
+
+    // a parameter (from the command line, a scene file or a caller) that
+    // can't be used
+    Invalid(String),
+
+    // ffmpeg failed to start or to finish
+    Process(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    // The CLI exits with these, so scripts can tell failures apart (clap
+    // uses 1 for usage errors and panics give 101)
+    pub fn exit_code(&self) -> i32 {
+        return match *self {
+            Error::Invalid(_) => 2,
+            Error::Io(_, _) => 3,
+            Error::Image(_, _) => 4,
+            Error::Process(_) => 5
+        };
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Error::Io(ref what, ref e) => write!(f, "{}: {}", what, e),
+            Error::Image(ref what, ref e) => write!(f, "{}: {}", what, e),
+            Error::Invalid(ref msg) => write!(f, "{}", msg),
+            Error::Process(ref msg) => write!(f, "{}", msg)
+        };
+    }
+}
+
+impl error::Error for Error {}
+
+// For `map_err`: wraps an I/O error with what we were doing
+pub fn io<S: Into<String>>(what: S) -> impl FnOnce(io::Error) -> Error {
+    let what = what.into();
+    return move |e| Error::Io(what, e);
+}
+
+// For `map_err`: wraps an image error with what we were doing
+pub fn image<S: Into<String>>(what: S)
+                              -> impl FnOnce(image::ImageError) -> Error {
+    let what = what.into();
+    return move |e| Error::Image(what, e);
+}
+
+// Shorthand for failing with `Error::Invalid`
+pub fn invalid<T, S: Into<String>>(msg: S) -> Result<T> {
+    return Err(Error::Invalid(msg.into()));
+}
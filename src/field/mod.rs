@@ -0,0 +1,697 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::path::Path;
+use png;
+use png::HasParameters;
+use tracer::Field;
+use grid::{Field2D, Lerp};
+
+// A flow field as stored on disk: the field itself plus everything
+// needed to render it the same way again
+pub struct FieldFile {
+    pub field: Field,
+    pub phase: f64,
+    pub generator: String
+}
+
+/*
+Native format, all numbers little-endian:
+
+    b"PFLD"
+    u32       version (1)
+    u32, u32  width, height
+    u8        kind: 0 = angle, 1 = curl, 2 = vector
+    f64       phase
+    u32       length of the generator description, then its UTF-8 bytes
+    f32...    row-major data, one value per cell for angles and two
+              (x then y) for vectors
+ */
+const MAGIC: &[u8; 4] = b"PFLD";
+const VERSION: u32 = 1;
+
+// Converts values in [0, 1] to unit vectors, the same way `Tracer`
+// turns an angle field into forces
+pub fn angles_to_vectors(angles: &Field2D<f64>,
+                         phase: f64) -> Field2D<(f64, f64)> {
+    return angles.par_map(|v| {
+        let angle = v * 2.0 * std::f64::consts::PI + phase;
+        (angle.cos(), angle.sin())
+    });
+}
+
+// Sobel gradient of a scalar field, replicating the edge values outward
+pub fn sobel(data: &Field2D<f64>) -> Field2D<(f64, f64)> {
+    let width = data.width() as isize;
+    let height = data.height() as isize;
+
+    let at = |x: isize, y: isize| {
+        let cx = x.max(0).min(width - 1) as usize;
+        let cy = y.max(0).min(height - 1) as usize;
+        data[(cx, cy)]
+    };
+
+    return Field2D::from_fn(data.width(), data.height(), |x, y| {
+        let (x, y) = (x as isize, y as isize);
+        let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+            - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+        let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+            - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+
+        (gx, gy)
+    });
+}
+
+// Rotates every vector by 90 degrees, so that a gradient field runs
+// along edges instead of across them
+pub fn perpendicular(vecs: &Field2D<(f64, f64)>) -> Field2D<(f64, f64)> {
+    return vecs.map(|&(x, y)| (-y, x));
+}
+
+pub fn normalize(vecs: &Field2D<(f64, f64)>) -> Field2D<(f64, f64)> {
+    return vecs.map(|&(x, y)| {
+        let mag = x.hypot(y);
+        if mag > 0.0 { (x / mag, y / mag) } else { (0.0, 0.0) }
+    });
+}
+
+// Blends two vector fields of the same size, `amount` of the way from
+// `a` to `b`, and rescales the result to unit length
+pub fn mix(a: &Field2D<(f64, f64)>, b: &Field2D<(f64, f64)>,
+           amount: f64) -> Field2D<(f64, f64)> {
+    assert!((0.0..=1.0).contains(&amount));
+
+    return normalize(&a.zip(b, |&va, &vb| Lerp::lerp(va, vb, amount)));
+}
+
+fn _grid<T>(width: usize, height: usize,
+            values: Vec<T>) -> io::Result<Field2D<T>> {
+    return Field2D::new(width, height, values)
+        .map_err(|e| _invalid(&e.to_string()));
+}
+
+// NaNs and infinities would poison the particles' positions, so a file
+// holding any is treated as malformed
+fn _finite(values: Vec<f64>) -> io::Result<Vec<f64>> {
+    if !values.iter().all(|v| v.is_finite()) {
+        return Err(_invalid("field values must be finite"));
+    }
+
+    return Ok(values);
+}
+
+fn _invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+// The product of a file's claimed dimensions, or an error if it can't
+// even be represented
+fn _checked_size(dims: &[usize]) -> io::Result<usize> {
+    return dims.iter()
+        .try_fold(1usize, |acc, &d| acc.checked_mul(d))
+        .ok_or_else(|| _invalid("field dimensions are too large"));
+}
+
+fn _extension(path: &Path) -> String {
+    return path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+}
+
+// Saves a field, picking the format from the extension: `.npy` and
+// `.png` (16-bit grayscale, angle fields only) bake the phase into the
+// values so other tools see the final angles; anything else uses the
+// native format
+pub fn save(path: &Path, ff: &FieldFile) -> io::Result<()> {
+    return match _extension(path).as_str() {
+        "npy" => _save_npy(path, ff),
+        "png" => _save_png16(path, ff),
+        _ => _save_native(path, ff)
+    };
+}
+
+// Whether `save` can only write angle fields to `path`
+pub fn angles_only(path: &Path) -> bool {
+    return _extension(path) == "png";
+}
+
+// Loads a field saved by `save`, or a 16-bit grayscale PNG or a `.npy`
+// array of shape (height, width) with angles in [0, 1] or (height,
+// width, 2) with vectors
+pub fn load(path: &Path) -> io::Result<FieldFile> {
+    return match _extension(path).as_str() {
+        "npy" => _load_npy(path),
+        "png" => _load_png16(path),
+        _ => _load_native(path)
+    };
+}
+
+fn _baked_angles(ff: &FieldFile) -> Option<Field2D<f64>> {
+    if let Field::Angle(ref angles) = ff.field {
+        let turn = ff.phase / (2.0 * std::f64::consts::PI);
+        return Some(angles.map(|a| {
+            let v = a + turn;
+            v - v.floor()
+        }));
+    }
+
+    return None;
+}
+
+fn _write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    return w.write_all(&[v as u8, (v >> 8) as u8,
+                         (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn _write_u64<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    _write_u32(w, v as u32)?;
+    return _write_u32(w, (v >> 32) as u32);
+}
+
+fn _read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    return Ok(u32::from(buf[0]) | u32::from(buf[1]) << 8
+              | u32::from(buf[2]) << 16 | u32::from(buf[3]) << 24);
+}
+
+fn _read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let lo = u64::from(_read_u32(r)?);
+    let hi = u64::from(_read_u32(r)?);
+    return Ok(lo | hi << 32);
+}
+
+fn _save_native(path: &Path, ff: &FieldFile) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+
+    let (kind, values): (u8, Vec<f64>) = match ff.field {
+        Field::Angle(ref angles) => (0, angles.data().to_vec()),
+        Field::Curl(ref vecs) =>
+            (1, vecs.iter().flat_map(|&(x, y)| vec![x, y]).collect()),
+        Field::Vector(ref vecs) =>
+            (2, vecs.iter().flat_map(|&(x, y)| vec![x, y]).collect())
+    };
+
+    w.write_all(MAGIC)?;
+    _write_u32(&mut w, VERSION)?;
+    _write_u32(&mut w, ff.field.width() as u32)?;
+    _write_u32(&mut w, ff.field.height() as u32)?;
+    w.write_all(&[kind])?;
+    _write_u64(&mut w, ff.phase.to_bits())?;
+    _write_u32(&mut w, ff.generator.len() as u32)?;
+    w.write_all(ff.generator.as_bytes())?;
+
+    for v in values {
+        _write_u32(&mut w, (v as f32).to_bits())?;
+    }
+
+    return Ok(());
+}
+
+fn _load_native(path: &Path) -> io::Result<FieldFile> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(_invalid("not a field file"));
+    }
+
+    if _read_u32(&mut r)? != VERSION {
+        return Err(_invalid("unsupported field file version"));
+    }
+
+    let width = _read_u32(&mut r)? as usize;
+    let height = _read_u32(&mut r)? as usize;
+
+    let mut kind = [0u8; 1];
+    r.read_exact(&mut kind)?;
+
+    let phase = f64::from_bits(_read_u64(&mut r)?);
+
+    let gen_len = _read_u32(&mut r)? as usize;
+
+    // never allocate more than the file could possibly hold: the header
+    // is the magic, version, size, kind, phase and generator length
+    let header_len = 4 + 4 + 4 + 4 + 1 + 8 + 4;
+    let channels = if kind[0] == 0 { 1 } else { 2 };
+    let count = _checked_size(&[width, height, channels])?;
+    let needed = _checked_size(&[count, 4])?
+        .checked_add(header_len + gen_len)
+        .ok_or_else(|| _invalid("field dimensions are too large"))?;
+    if needed as u64 > file_len {
+        return Err(_invalid("field file is truncated"));
+    }
+
+    let mut gen = vec![0u8; gen_len];
+    r.read_exact(&mut gen)?;
+    let generator = String::from_utf8(gen)
+        .map_err(|_| _invalid("generator description is not UTF-8"))?;
+
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(f64::from(f32::from_bits(_read_u32(&mut r)?)));
+    }
+    let values = _finite(values)?;
+
+    if !phase.is_finite() {
+        return Err(_invalid("field phase must be finite"));
+    }
+
+    let pairs = |values: Vec<f64>| values.chunks(2)
+        .map(|c| (c[0], c[1]))
+        .collect::<Vec<(f64, f64)>>();
+
+    let field = match kind[0] {
+        0 => Field::Angle(_grid(width, height, values)?),
+        1 => Field::Curl(_grid(width, height, pairs(values))?),
+        2 => Field::Vector(_grid(width, height, pairs(values))?),
+        _ => return Err(_invalid("unknown field kind"))
+    };
+
+    return Ok(FieldFile {
+        field,
+        phase,
+        generator
+    });
+}
+
+fn _save_png16(path: &Path, ff: &FieldFile) -> io::Result<()> {
+    let angles = match _baked_angles(ff) {
+        Some(a) => a,
+        None => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only angle fields can be saved as PNG"))
+    };
+
+    let w = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(w, angles.width() as u32,
+                                        angles.height() as u32);
+    encoder.set(png::ColorType::Grayscale).set(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header()?;
+
+    // 16-bit PNG samples are big-endian
+    let mut data = Vec::with_capacity(angles.data().len() * 2);
+    for a in angles.iter() {
+        let v = (a * 65535.0).round() as u16;
+        data.push((v >> 8) as u8);
+        data.push(v as u8);
+    }
+
+    writer.write_image_data(&data)?;
+    return Ok(());
+}
+
+fn _load_png16(path: &Path) -> io::Result<FieldFile> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+
+    // keep the full 16 bits instead of the default stripping to 8
+    decoder.set(png::Transformations::IDENTITY);
+
+    let (info, mut reader) = decoder.read_info()?;
+
+    let bytes = match info.bit_depth {
+        png::BitDepth::Sixteen => 2,
+        png::BitDepth::Eight => 1,
+        _ => return Err(_invalid("field PNGs must be 8 or 16 bits deep"))
+    };
+
+    if info.color_type == png::ColorType::Indexed {
+        return Err(_invalid("field PNGs must not use a palette"));
+    }
+
+    let mut buf = vec![0u8; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+
+    let samples = info.color_type.samples();
+    let max = if bytes == 2 { 65535.0 } else { 255.0 };
+
+    // only the first channel is used, so RGB images work too
+    let mut angles = Vec::with_capacity((info.width * info.height) as usize);
+    for y in 0..info.height as usize {
+        let row = &buf[y * info.line_size..(y + 1) * info.line_size];
+        for x in 0..info.width as usize {
+            let off = x * samples * bytes;
+            let v = if bytes == 2 {
+                u32::from(row[off]) << 8 | u32::from(row[off + 1])
+            } else {
+                u32::from(row[off])
+            };
+            angles.push(f64::from(v) / max);
+        }
+    }
+
+    return Ok(FieldFile {
+        field: Field::Angle(_grid(info.width as usize, info.height as usize,
+                                  angles)?),
+        phase: 0.0,
+        generator: format!("imported from {}", path.display())
+    });
+}
+
+fn _save_npy(path: &Path, ff: &FieldFile) -> io::Result<()> {
+    let (width, height) = (ff.field.width(), ff.field.height());
+    let (shape, values): (String, Vec<f64>) = match _baked_angles(ff) {
+        Some(angles) => (format!("({}, {})", height, width),
+                         angles.into_vec()),
+        None => {
+            let vecs = match ff.field {
+                Field::Curl(ref v) | Field::Vector(ref v) => v,
+                Field::Angle(_) => unreachable!()
+            };
+            (format!("({}, {}, 2)", height, width),
+             vecs.iter().flat_map(|&(x, y)| vec![x, y]).collect())
+        }
+    };
+
+    // the header is padded with spaces so the data starts on a
+    // 16-byte boundary, and ends with a newline
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape);
+    while (10 + header.len() + 1) % 16 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&[header.len() as u8, (header.len() >> 8) as u8])?;
+    w.write_all(header.as_bytes())?;
+
+    for v in values {
+        _write_u32(&mut w, (v as f32).to_bits())?;
+    }
+
+    return Ok(());
+}
+
+fn _npy_entry<'a>(header: &'a str, key: &str) -> io::Result<&'a str> {
+    let start = header.find(&format!("'{}':", key))
+        .ok_or_else(|| _invalid(&format!("npy header has no {}", key)))?;
+    return Ok(header[start + key.len() + 3..].trim_start());
+}
+
+fn _load_npy(path: &Path) -> io::Result<FieldFile> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut r = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic[0..6] != b"\x93NUMPY" {
+        return Err(_invalid("not an npy file"));
+    }
+
+    let header_len = if magic[6] == 1 {
+        let mut len = [0u8; 2];
+        r.read_exact(&mut len)?;
+        usize::from(len[0]) | usize::from(len[1]) << 8
+    } else {
+        _read_u32(&mut r)? as usize
+    };
+
+    if header_len as u64 > file_len {
+        return Err(_invalid("npy header is truncated"));
+    }
+
+    let mut header = vec![0u8; header_len];
+    r.read_exact(&mut header)?;
+    let header = String::from_utf8(header)
+        .map_err(|_| _invalid("npy header is not text"))?;
+
+    let descr = _npy_entry(&header, "descr")?;
+    let descr = descr.trim_start_matches('\'');
+    let descr = &descr[..descr.find('\'').unwrap_or(0)];
+
+    if _npy_entry(&header, "fortran_order")?.starts_with("True") {
+        return Err(_invalid("Fortran-ordered npy arrays are not supported"));
+    }
+
+    let shape = _npy_entry(&header, "shape")?;
+    let shape = shape.get(1..shape.find(')').unwrap_or(1)).unwrap_or("");
+    let dims: Vec<usize> = shape.split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| _invalid("bad npy shape")))
+        .collect::<io::Result<Vec<usize>>>()?;
+
+    let (height, width, channels) = match dims.len() {
+        2 => (dims[0], dims[1], 1),
+        3 if dims[2] == 2 => (dims[0], dims[1], 2),
+        _ => return Err(_invalid("npy array must be (h, w) or (h, w, 2)"))
+    };
+
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+
+    let count = _checked_size(&[width, height, channels])?;
+    let fits = |item: usize| count.checked_mul(item)
+        .is_some_and(|n| data.len() >= n);
+    let values: Vec<f64> = match descr {
+        "<f4" if fits(4) => data.chunks(4).take(count)
+            .map(|b| f64::from(f32::from_bits(
+                u32::from(b[0]) | u32::from(b[1]) << 8
+                    | u32::from(b[2]) << 16 | u32::from(b[3]) << 24)))
+            .collect(),
+        "<f8" if fits(8) => data.chunks(8).take(count)
+            .map(|b| f64::from_bits(
+                b.iter().rev().fold(0u64, |acc, &v| acc << 8 | u64::from(v))))
+            .collect(),
+        "|u1" if fits(1) => data.iter().take(count)
+            .map(|&v| f64::from(v) / 255.0)
+            .collect(),
+        "<u2" if fits(2) => data.chunks(2).take(count)
+            .map(|b| f64::from(u16::from(b[0]) | u16::from(b[1]) << 8)
+                 / 65535.0)
+            .collect(),
+        _ => return Err(_invalid("unsupported or truncated npy data"))
+    };
+    let values = _finite(values)?;
+
+    let field = if channels == 1 {
+        Field::Angle(_grid(width, height, values)?)
+    } else {
+        Field::Vector(_grid(width, height, values.chunks(2)
+                            .map(|c| (c[0], c[1]))
+                            .collect())?)
+    };
+
+    return Ok(FieldFile {
+        field,
+        phase: 0.0,
+        generator: format!("imported from {}", path.display())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use grid::Field2D;
+    use tracer::Field;
+    use super::{sobel, perpendicular, normalize, mix, save, load,
+                FieldFile};
+
+    fn _path(name: &str) -> PathBuf {
+        return env::temp_dir().join(format!("perlin-field-test-{}", name));
+    }
+
+    // Saves and reloads a field, removing the file again
+    fn _round_trip(name: &str, ff: &FieldFile) -> FieldFile {
+        let path = _path(name);
+        save(&path, ff).unwrap();
+        let back = load(&path);
+        fs::remove_file(&path).unwrap();
+        return back.unwrap();
+    }
+
+    fn _angles(ff: &FieldFile) -> &Field2D<f64> {
+        return match ff.field {
+            Field::Angle(ref a) => a,
+            _ => panic!("expected an angle field")
+        };
+    }
+
+    fn _vectors(ff: &FieldFile) -> &Field2D<(f64, f64)> {
+        return match ff.field {
+            Field::Curl(ref v) | Field::Vector(ref v) => v,
+            _ => panic!("expected a vector field")
+        };
+    }
+
+    fn _angle_file(phase: f64) -> FieldFile {
+        return FieldFile {
+            field: Field::Angle(Field2D::from_fn(5, 3, |x, y| {
+                (x * 3 + y) as f64 / 20.0
+            })),
+            phase,
+            generator: String::from("test")
+        };
+    }
+
+    #[test]
+    fn sobel_follows_a_ramp() {
+        // rises by 1 per column, so the Sobel kernel sees 8 per cell
+        let ramp = Field2D::from_fn(6, 5, |x, _| x as f64);
+        let grad = sobel(&ramp);
+
+        for y in 0..5 {
+            for x in 1..5 {
+                assert_eq!(grad[(x, y)], (8.0, 0.0));
+            }
+        }
+
+        // the replicated edge halves the difference there
+        assert_eq!(grad[(0, 2)], (4.0, 0.0));
+    }
+
+    #[test]
+    fn perpendicular_turns_a_quarter() {
+        let vecs = Field2D::filled(2, 2, (3.0, 1.0));
+        let turned = perpendicular(&vecs);
+        assert_eq!(turned[(1, 1)], (-1.0, 3.0));
+        assert_eq!(vecs[(1, 1)].0 * turned[(1, 1)].0
+                   + vecs[(1, 1)].1 * turned[(1, 1)].1, 0.0);
+    }
+
+    #[test]
+    fn normalize_keeps_zero_vectors() {
+        let vecs = Field2D::from_fn(2, 1, |x, _| {
+            if x == 0 { (3.0, 4.0) } else { (0.0, 0.0) }
+        });
+        let unit = normalize(&vecs);
+        assert_eq!(unit[(0, 0)], (0.6, 0.8));
+        assert_eq!(unit[(1, 0)], (0.0, 0.0));
+    }
+
+    #[test]
+    fn mix_blends_between_the_fields() {
+        let a = Field2D::filled(3, 2, (1.0, 0.0));
+        let b = Field2D::filled(3, 2, (0.0, 1.0));
+
+        assert_eq!(mix(&a, &b, 0.0)[(2, 1)], (1.0, 0.0));
+        assert_eq!(mix(&a, &b, 1.0)[(2, 1)], (0.0, 1.0));
+
+        let (x, y) = mix(&a, &b, 0.5)[(0, 0)];
+        let half = 0.5_f64.sqrt();
+        assert!((x - half).abs() < 1e-12 && (y - half).abs() < 1e-12);
+    }
+
+    #[test]
+    fn native_files_round_trip() {
+        let ff = _angle_file(1.5);
+        let back = _round_trip("angle.pfld", &ff);
+        assert_eq!(back.phase, 1.5);
+        assert_eq!(back.generator, "test");
+        for (a, b) in _angles(&ff).iter().zip(_angles(&back).iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        let curl = FieldFile {
+            field: Field::Curl(Field2D::from_fn(4, 2, |x, y| {
+                (x as f64 * 0.25, -(y as f64))
+            })),
+            phase: 0.0,
+            generator: String::from("curl")
+        };
+        let back = _round_trip("curl.pfld", &curl);
+        assert!(matches!(back.field, Field::Curl(_)));
+        assert_eq!(_vectors(&back).data(), _vectors(&curl).data());
+    }
+
+    #[test]
+    fn npy_and_png_bake_in_the_phase() {
+        // a quarter turn
+        let ff = _angle_file(0.5 * ::std::f64::consts::PI);
+        let expected = _angles(&ff).map(|a| (a + 0.25).fract());
+
+        for &(name, tolerance) in &[("angle.npy", 1e-6),
+                                    ("angle.png", 1.0 / 65535.0)] {
+            let back = _round_trip(name, &ff);
+            assert_eq!(back.phase, 0.0);
+            assert_eq!((_angles(&back).width(), _angles(&back).height()),
+                       (5, 3));
+            for (a, b) in expected.iter().zip(_angles(&back).iter()) {
+                assert!((a - b).abs() <= tolerance, "{}: {} vs {}",
+                        name, a, b);
+            }
+        }
+
+        let vecs = FieldFile {
+            field: Field::Vector(Field2D::filled(3, 2, (0.5, -0.25))),
+            phase: 0.0,
+            generator: String::new()
+        };
+        let back = _round_trip("vectors.npy", &vecs);
+        assert_eq!(_vectors(&back).data(), _vectors(&vecs).data());
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let path = _path("truncated.pfld");
+        save(&path, &_angle_file(0.0)).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn huge_claimed_sizes_are_errors() {
+        let path = _path("huge.pfld");
+        save(&path, &_angle_file(0.0)).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        // the width and height follow the magic and version
+        for b in &mut bytes[8..16] {
+            *b = 0xff;
+        }
+        fs::write(&path, &bytes).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn non_finite_values_are_invalid_data() {
+        let mut ff = _angle_file(0.0);
+        if let Field::Angle(ref mut a) = ff.field {
+            a[(2, 1)] = f64::NAN;
+        }
+
+        for name in &["nan.pfld", "nan.npy"] {
+            let path = _path(name);
+            save(&path, &ff).unwrap();
+            let loaded = load(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.err().map(|e| e.kind()),
+                       Some(io::ErrorKind::InvalidData), "{}", name);
+        }
+    }
+}
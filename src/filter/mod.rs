@@ -0,0 +1,175 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::f64::consts::PI;
+use grid::{Field2D, Weighted};
+
+#[derive(Clone, Copy)]
+pub enum Filter {
+    // the mean of each block of samples
+    Box,
+
+    // windowed sinc with three lobes: the sharpest, but it can ring
+    // next to hard edges
+    Lanczos,
+
+    // Mitchell-Netravali with B = C = 1/3, a compromise between
+    // blurring and ringing
+    Mitchell
+}
+
+impl Filter {
+    // how far the kernel reaches, in output pixels
+    fn radius(&self) -> f64 {
+        return match *self {
+            Filter::Box => 0.5,
+            Filter::Lanczos => 3.0,
+            Filter::Mitchell => 2.0
+        };
+    }
+
+    fn weight(&self, d: f64) -> f64 {
+        let x = d.abs();
+
+        return match *self {
+            Filter::Box => if x < 0.5 { 1.0 } else { 0.0 },
+
+            Filter::Lanczos => {
+                if x == 0.0 {
+                    1.0
+                } else if x < 3.0 {
+                    3.0 * (PI * x).sin() * (PI * x / 3.0).sin()
+                        / (PI * PI * x * x)
+                } else {
+                    0.0
+                }
+            },
+
+            Filter::Mitchell => {
+                if x < 1.0 {
+                    (7.0 * x * x * x - 12.0 * x * x + 16.0 / 3.0) / 6.0
+                } else if x < 2.0 {
+                    (-7.0 / 3.0 * x * x * x + 12.0 * x * x
+                     - 20.0 * x + 32.0 / 3.0) / 6.0
+                } else {
+                    0.0
+                }
+            }
+        };
+    }
+}
+
+// For each of `len` output pixels, the first of the `len * factor`
+// samples it reads and the weight of each sample from there on. The
+// weights are renormalized, so the edges don't darken.
+fn _taps(filter: Filter, len: usize, factor: usize) -> Vec<(usize, Vec<f64>)> {
+    let samples = (len * factor) as i64;
+    let reach = filter.radius() * factor as f64;
+
+    return (0..len).map(|i| {
+        let center = (i as f64 + 0.5) * factor as f64;
+        let first = ((center - reach).floor() as i64).max(0);
+        let last = ((center + reach).ceil() as i64).min(samples - 1);
+
+        let mut weights: Vec<f64> = (first..(last + 1))
+            .map(|j| filter.weight((j as f64 + 0.5 - center)
+                                   / factor as f64))
+            .collect();
+
+        let total: f64 = weights.iter().sum();
+        if total != 0.0 {
+            for w in &mut weights {
+                *w /= total;
+            }
+        }
+
+        (first as usize, weights)
+    }).collect();
+}
+
+// Shrinks a grid by `factor` along both axes, filtering out the detail
+// the smaller grid can't show instead of letting it alias
+pub fn downsample<T: Weighted>(grid: &Field2D<T>, factor: usize,
+                               filter: Filter) -> Field2D<T> {
+    // the render validates the supersampling factor and sizes its
+    // canvas to match
+    debug_assert!(factor > 0 && grid.width().is_multiple_of(factor)
+                  && grid.height().is_multiple_of(factor),
+                  "A {}x{} grid can't be shrunk by {}",
+                  grid.width(), grid.height(), factor);
+
+    let width = grid.width() / factor;
+    let height = grid.height() / factor;
+    let xs = _taps(filter, width, factor);
+    let ys = _taps(filter, height, factor);
+
+    // one axis at a time
+    let rows = Field2D::par_from_fn(width, grid.height(), |x, y| {
+        let (first, ref weights) = xs[x];
+        let mut to_r = T::zero();
+        for (k, &w) in weights.iter().enumerate() {
+            to_r.add_weighted(&grid[(first + k, y)], w);
+        }
+        to_r
+    });
+
+    return Field2D::par_from_fn(width, height, |x, y| {
+        let (first, ref weights) = ys[y];
+        let mut to_r = T::zero();
+        for (k, &w) in weights.iter().enumerate() {
+            to_r.add_weighted(&rows[(x, first + k)], w);
+        }
+        to_r
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{downsample, Filter};
+    use grid::Field2D;
+
+    #[test]
+    fn box_averages_each_block() {
+        let g = Field2D::from_fn(6, 4, |x, y| (y * 6 + x) as f64);
+        let d = downsample(&g, 2, Filter::Box);
+
+        assert_eq!(d.width(), 3);
+        assert_eq!(d.height(), 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                let mean = (g[(2 * x, 2 * y)] + g[(2 * x + 1, 2 * y)]
+                            + g[(2 * x, 2 * y + 1)]
+                            + g[(2 * x + 1, 2 * y + 1)]) / 4.0;
+                assert!((d[(x, y)] - mean).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn every_filter_keeps_a_constant() {
+        let g = Field2D::filled(24, 12, 0.75);
+
+        for &filter in &[Filter::Box, Filter::Lanczos, Filter::Mitchell] {
+            for &factor in &[1, 2, 3, 4] {
+                let d = downsample(&g, factor, filter);
+                assert!(d.iter().all(|v| (v - 0.75).abs() < 1e-12));
+            }
+        }
+    }
+}
@@ -0,0 +1,380 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::f64;
+use std::ops::{Index, IndexMut};
+use std::slice;
+use rayon::prelude::*;
+use color::Rgb;
+use error;
+use error::Result;
+
+// A width x height array of values, stored row-major: the value at
+// (x, y) lives at y * width + x, with x running left to right and y
+// top to bottom. Noise, flow fields, flux and images all use this
+// layout, so anything that indexes a grid by (x, y) agrees on it.
+#[derive(Clone)]
+pub struct Field2D<T> {
+    width: usize,
+    height: usize,
+    data: Vec<T>
+}
+
+impl<T> Field2D<T> {
+    // Wraps row-major data, which must hold exactly width * height values
+    pub fn new(width: usize, height: usize,
+               data: Vec<T>) -> Result<Field2D<T>> {
+        if data.len() != width * height {
+            return error::invalid(format!(
+                "A {}x{} grid needs {} values, not {}",
+                width, height, width * height, data.len()));
+        }
+
+        return Ok(Field2D {
+            width,
+            height,
+            data
+        });
+    }
+
+    // Fills the grid row by row with f(x, y)
+    pub fn from_fn<F>(width: usize, height: usize, mut f: F) -> Field2D<T>
+        where F: FnMut(usize, usize) -> T {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+
+        return Field2D {
+            width,
+            height,
+            data
+        };
+    }
+
+    pub fn width(&self) -> usize {
+        return self.width;
+    }
+
+    pub fn height(&self) -> usize {
+        return self.height;
+    }
+
+    pub fn index_of(&self, x: usize, y: usize) -> usize {
+        // an x past the width would otherwise land in the next row
+        assert!(x < self.width && y < self.height,
+                "({}, {}) is outside a {}x{} grid",
+                x, y, self.width, self.height);
+        return y * self.width + x;
+    }
+
+    // The cell containing (fx, fy) in [0, 1], with 1 falling in the
+    // last row or column
+    pub fn cell_at(&self, fx: f64, fy: f64) -> (usize, usize) {
+        let x = ((fx * self.width as f64) as usize).min(self.width - 1);
+        let y = ((fy * self.height as f64) as usize).min(self.height - 1);
+        return (x, y);
+    }
+
+    // All of the values, row-major
+    pub fn data(&self) -> &[T] {
+        return &self.data;
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        return self.data;
+    }
+
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, T> {
+        return self.data.iter();
+    }
+
+    pub fn map<U, F: FnMut(&T) -> U>(&self, f: F) -> Field2D<U> {
+        return self._with_data(self.data.iter().map(f).collect());
+    }
+
+    // Combines two grids of the same size value by value
+    pub fn zip<U, V, F>(&self, other: &Field2D<U>, mut f: F) -> Field2D<V>
+        where F: FnMut(&T, &U) -> V {
+        self._check_size(other);
+        return self._with_data(self.data.iter().zip(other.data.iter())
+                               .map(|(a, b)| f(a, b))
+                               .collect());
+    }
+
+    // Updates every value from the matching value of another grid of
+    // the same size, e.g. to accumulate one grid into another
+    pub fn zip_mut<U, F>(&mut self, other: &Field2D<U>, mut f: F)
+        where F: FnMut(&mut T, &U) {
+        self._check_size(other);
+        for (a, b) in self.data.iter_mut().zip(other.data.iter()) {
+            f(a, b);
+        }
+    }
+
+    fn _check_size<U>(&self, other: &Field2D<U>) {
+        assert!(self.width == other.width && self.height == other.height,
+                "Can't combine a {}x{} grid with a {}x{} one",
+                self.width, self.height, other.width, other.height);
+    }
+
+    fn _with_data<U>(&self, data: Vec<U>) -> Field2D<U> {
+        debug_assert_eq!(data.len(), self.data.len());
+        return Field2D {
+            width: self.width,
+            height: self.height,
+            data
+        };
+    }
+}
+
+impl<T: Send + Sync> Field2D<T> {
+    // Like `from_fn`, but evaluates f on all cores
+    pub fn par_from_fn<F>(width: usize, height: usize, f: F) -> Field2D<T>
+        where F: Fn(usize, usize) -> T + Sync + Send {
+        let data = (0..width * height).into_par_iter()
+            .map(|idx| f(idx % width, idx / width))
+            .collect();
+
+        return Field2D {
+            width,
+            height,
+            data
+        };
+    }
+
+    // Like `map`, but on all cores
+    pub fn par_map<U, F>(&self, f: F) -> Field2D<U>
+        where U: Send, F: Fn(&T) -> U + Sync + Send {
+        return self._with_data(self.data.par_iter().map(f).collect());
+    }
+}
+
+impl<T: Clone> Field2D<T> {
+    pub fn filled(width: usize, height: usize, value: T) -> Field2D<T> {
+        return Field2D {
+            width,
+            height,
+            data: vec![value; width * height]
+        };
+    }
+}
+
+impl Field2D<f64> {
+    pub fn min(&self) -> f64 {
+        return self.data.iter().fold(f64::INFINITY, |a, &b| a.min(b));
+    }
+
+    pub fn max(&self) -> f64 {
+        return self.data.iter().fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+    }
+
+    // Maps [lo, hi] linearly onto [0, 1]. An empty range (a flat grid,
+    // or octaves that cancel out) maps everything to 0.
+    pub fn rescale(&self, lo: f64, hi: f64) -> Field2D<f64> {
+        let range = hi - lo;
+        if range == 0.0 {
+            return self.map(|_| 0.0);
+        }
+
+        return self.map(|v| (v - lo) / range);
+    }
+
+    // Stretches the values to exactly cover [0, 1]
+    pub fn normalize(&self) -> Field2D<f64> {
+        return self.rescale(self.min(), self.max());
+    }
+}
+
+// Values that can be blended, for sampling between grid cells
+pub trait Lerp: Copy {
+    // `t` of the way from `a` to `b`
+    fn lerp(a: Self, b: Self, t: f64) -> Self;
+}
+
+impl Lerp for f64 {
+    fn lerp(a: f64, b: f64, t: f64) -> f64 {
+        return a + (b - a) * t;
+    }
+}
+
+impl Lerp for (f64, f64) {
+    fn lerp(a: (f64, f64), b: (f64, f64), t: f64) -> (f64, f64) {
+        return (f64::lerp(a.0, b.0, t), f64::lerp(a.1, b.1, t));
+    }
+}
+
+impl Lerp for Rgb {
+    fn lerp(a: Rgb, b: Rgb, t: f64) -> Rgb {
+        return (f64::lerp(a.0, b.0, t), f64::lerp(a.1, b.1, t),
+                f64::lerp(a.2, b.2, t));
+    }
+}
+
+// Values that can be summed with weights, for filtering
+pub trait Weighted: Copy + Send + Sync {
+    fn zero() -> Self;
+    fn add_weighted(&mut self, other: &Self, w: f64);
+}
+
+impl Weighted for f64 {
+    fn zero() -> f64 {
+        return 0.0;
+    }
+
+    fn add_weighted(&mut self, other: &f64, w: f64) {
+        *self += other * w;
+    }
+}
+
+impl Weighted for Rgb {
+    fn zero() -> Rgb {
+        return (0.0, 0.0, 0.0);
+    }
+
+    fn add_weighted(&mut self, other: &Rgb, w: f64) {
+        self.0 += other.0 * w;
+        self.1 += other.1 * w;
+        self.2 += other.2 * w;
+    }
+}
+
+impl<T: Lerp> Field2D<T> {
+    // Bilinear interpolation at (fx, fy) in [0, 1], treating each
+    // value as the center of its cell; positions past the outer cell
+    // centers take the edge values
+    pub fn sample(&self, fx: f64, fy: f64) -> T {
+        let gx = (fx * self.width as f64 - 0.5)
+            .max(0.0).min((self.width - 1) as f64);
+        let gy = (fy * self.height as f64 - 0.5)
+            .max(0.0).min((self.height - 1) as f64);
+
+        let x0 = gx as usize;
+        let y0 = gy as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let tx = gx - x0 as f64;
+        let ty = gy - y0 as f64;
+
+        let top = T::lerp(self[(x0, y0)], self[(x1, y0)], tx);
+        let bottom = T::lerp(self[(x0, y1)], self[(x1, y1)], tx);
+        return T::lerp(top, bottom, ty);
+    }
+}
+
+impl<T> Index<(usize, usize)> for Field2D<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        return &self.data[self.index_of(x, y)];
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Field2D<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        let idx = self.index_of(x, y);
+        return &mut self.data[idx];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Field2D;
+
+    fn _ramp(width: usize, height: usize) -> Field2D<f64> {
+        return Field2D::from_fn(width, height,
+                                |x, y| (y * width + x) as f64);
+    }
+
+    #[test]
+    fn par_from_fn_matches_from_fn() {
+        let par = Field2D::par_from_fn(7, 5, |x, y| (y * 7 + x) as f64);
+        assert_eq!(par.data(), _ramp(7, 5).data());
+    }
+
+    #[test]
+    fn zip_and_zip_mut_agree() {
+        let a = _ramp(4, 3);
+        let b = a.map(|v| v * 2.0);
+
+        let summed = a.zip(&b, |&x, &y| x + y);
+        let mut acc = a.clone();
+        acc.zip_mut(&b, |x, &y| *x += y);
+
+        assert_eq!(summed.data(), acc.data());
+        assert_eq!(summed[(3, 2)], 33.0);
+    }
+
+    #[test]
+    fn normalize_covers_the_unit_interval() {
+        let n = _ramp(6, 2).normalize();
+        assert_eq!(n.min(), 0.0);
+        assert_eq!(n.max(), 1.0);
+    }
+
+    #[test]
+    fn normalize_flattens_a_constant_grid_to_zero() {
+        let n = Field2D::filled(5, 4, 0.3).normalize();
+        assert!(n.iter().all(|&v| v == 0.0));
+        assert!(_ramp(3, 3).rescale(2.0, 2.0).iter().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn sample_hits_cell_centers_and_blends_between() {
+        let g = _ramp(4, 3);
+
+        for y in 0..3 {
+            for x in 0..4 {
+                let fx = (x as f64 + 0.5) / 4.0;
+                let fy = (y as f64 + 0.5) / 3.0;
+                assert!((g.sample(fx, fy) - g[(x, y)]).abs() < 1e-9);
+            }
+        }
+
+        // halfway between the first two cells of the first row
+        assert!((g.sample(0.25, 0.5 / 3.0) - 0.5).abs() < 1e-9);
+
+        // the edges are clamped to the outer cells
+        assert_eq!(g.sample(0.0, 0.0), g[(0, 0)]);
+        assert_eq!(g.sample(1.0, 1.0), g[(3, 2)]);
+    }
+
+    #[test]
+    fn x_runs_along_rows() {
+        // wider than it is tall, and only changing from left to right
+        let g = Field2D::from_fn(5, 3, |x, _| x as f64 / 4.0);
+
+        assert_eq!(&g.data()[..5], &[0.0, 0.25, 0.5, 0.75, 1.0]);
+        assert_eq!(g[(4, 0)], 1.0);
+        assert_eq!(g[(4, 2)], 1.0);
+        for &fy in &[0.0, 0.5, 1.0] {
+            assert!((g.sample(0.5, fy) - 0.5).abs() < 1e-9);
+            assert_eq!(g.sample(1.0, fy), 1.0);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn indices_past_the_width_panic() {
+        let g = _ramp(5, 3);
+        let _ = g[(5, 0)];
+    }
+}
@@ -0,0 +1,64 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::path::Path;
+use image;
+use color::Rgb;
+use grid::Field2D;
+use error;
+use error::Result;
+
+// Loads an image as luminance values in [0, 1], stretched to
+// width x height
+pub fn load_grayscale(path: &Path, width: usize,
+                      height: usize) -> Result<Field2D<f64>> {
+    let img = image::open(path)
+        .map_err(error::image(format!("Could not open input image {}",
+                                      path.display())))?
+        .to_luma();
+
+    let resized = image::imageops::resize(&img,
+                                          width as u32, height as u32,
+                                          image::FilterType::Triangle);
+
+    return Ok(Field2D::from_fn(width, height, |x, y| {
+        let px = resized.get_pixel(x as u32, y as u32);
+        f64::from(px.data[0]) / 255.0
+    }));
+}
+
+// Loads an image as RGB values in [0, 1], stretched to width x height
+pub fn load_rgb(path: &Path, width: usize,
+                height: usize) -> Result<Field2D<Rgb>> {
+    let img = image::open(path)
+        .map_err(error::image(format!("Could not open input image {}",
+                                      path.display())))?
+        .to_rgb();
+
+    let resized = image::imageops::resize(&img,
+                                          width as u32, height as u32,
+                                          image::FilterType::Triangle);
+
+    return Ok(Field2D::from_fn(width, height, |x, y| {
+        let px = resized.get_pixel(x as u32, y as u32);
+        (f64::from(px.data[0]) / 255.0,
+         f64::from(px.data[1]) / 255.0,
+         f64::from(px.data[2]) / 255.0)
+    }));
+}
@@ -18,404 +18,1055 @@
 // 
 // < end copyright > 
 #![allow(unknown_lints)] // for clippy
-#![allow(explicit_iter_loop, needless_return)]
+#![allow(clippy::explicit_iter_loop, clippy::needless_return)]
 
 extern crate rand;
 extern crate image;
 extern crate rayon;
 extern crate clap;
 extern crate mktemp;
-
-
+extern crate png;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+extern crate serde_json;
+
+
+mod error;
+mod grid;
+mod filter;
+mod post;
 mod perlin;
 mod tracer;
+mod seeding;
+mod input;
+mod color;
+mod field;
+mod scene;
+mod render;
+mod metadata;
+mod batch;
+mod sheet;
 
-use std::fs::File;
-use rayon::prelude::*;
-use std::cmp;
 use std::path::Path;
-use rand::distributions::{IndependentSample, Range};
+use std::process;
+use std::result;
+use std::str::FromStr;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use error::{Error, Result};
+use scene::{Scene, Layer};
+
+// The value of a flag only if it was given on the command line, so that
+// clap's defaults never override a scene file
+fn _given<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    if matches.occurrences_of(name) == 0 {
+        return None;
+    }
 
+    return matches.value_of(name);
+}
 
-use perlin::perlin;
-use tracer::Tracer;
-use clap::{App, Arg};
-use mktemp::Temp;
-use std::process::Command;
-use std::f64;
+fn _parse<T: FromStr>(matches: &ArgMatches, name: &str,
+                      what: &str) -> Result<Option<T>> {
+    return match _given(matches, name) {
+        None => Ok(None),
+        Some(v) => v.parse::<T>().map(Some).map_err(|_| Error::Invalid(
+            format!("{} must be a number, not {}", what, v)))
+    };
+}
 
-fn _lerp(a: f64, b: f64, w: f64) -> f64 {
-    assert!(w >= 0.0);
-    assert!(w <= 1.0);
-    return w*a + (1.0 - w)*b;
+fn _owned(matches: &ArgMatches, name: &str) -> Option<String> {
+    return _given(matches, name).map(String::from);
 }
 
-fn write_image(data: &[f64],
-               img_width: usize, img_height: usize,
-               name: &Path,
-               color_func: u8) {
-
-    let mut imgbuf = image::ImageBuffer::new(img_width as u32,
-                                             img_height as u32);
-    
-    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-        let val = data[(y*img_width as u32 + x) as usize];
-
-        match color_func {
-            0 => {
-                *pixel = image::Rgb([(val.sqrt()*180.0) as u8,
-                                     20,
-                                     (val.sqrt()*225.0) as u8]);
-            },
-
-            1 => {
-                *pixel = image::Rgb([(val.sqrt()*20.0) as u8,
-                                     (val.sqrt()*180.0) as u8,
-                                     (val.sqrt()*225.0) as u8]);
-            },
-
-            2 => {                
-                *pixel = image::Rgb([(val.sqrt()*200.0) as u8,
-                                     (val.sqrt()*180.0) as u8,
-                                     (val.sqrt()*90.0) as u8]);
-            },
-
-            3 => {
-                let c1_r = 125.0;
-                let c1_g = 186.0;
-                let c1_b = 182.0;
-                
-                let c2_r = 46.0;
-                let c2_g = 59.0;
-                let c2_b = 65.0;
-                
-                *pixel = image::Rgb([_lerp(c1_r, c2_r, val.sqrt()) as u8,
-                                     _lerp(c1_g, c2_g, val.sqrt()) as u8,
-                                     _lerp(c1_b, c2_b, val.sqrt()) as u8]);
-            },
-
-            _ => {
-                panic!("Unknown color function!");
-            }
+// A switch and its --no-... counterpart, so the command line can turn
+// off what a scene file turned on; neither leaves the scene alone
+fn _switch(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.is_present(name) {
+        return Some(true);
+    }
+
+    if matches.is_present(format!("no {}", name)) {
+        return Some(false);
+    }
+
+    return None;
+}
+
+fn apply_args(scene: &mut Scene, matches: &ArgMatches) -> Result<()> {
+    if let Some(v) = _parse(matches, "number of jobs", "Number of jobs")? {
+        scene.jobs = v;
+    }
+    if let Some(v) = _parse(matches, "seed", "Seed")? {
+        scene.seed = Some(v);
+    }
+
+    let output = &mut scene.output;
+    if let Some(v) = _owned(matches, "output") { output.name = v; }
+    if let Some(v) = _parse(matches, "width", "Image width")? { output.width = v; }
+    if let Some(v) = _parse(matches, "height", "Image height")? { output.height = v; }
+    if let Some(v) = _parse(matches, "ssaa", "Supersampling factor")? { output.ssaa = v; }
+    if let Some(v) = _owned(matches, "filter") { output.filter = v; }
+    if let Some(v) = _owned(matches, "format") { output.format = v; }
+    if let Some(v) = _switch(matches, "dump config") { output.dump_config = v; }
+    if let Some(v) = _switch(matches, "transparent") { output.transparent = v; }
+
+    let noise = &mut scene.noise;
+    if let Some(v) = _parse(matches, "frequency", "Frequency")? { noise.frequency = v; }
+    if let Some(v) = _switch(matches, "tileable") { noise.tileable = v; }
+    if let Some(v) = _given(matches, "offset") {
+        let parts = v.split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<result::Result<Vec<f64>, _>>();
+        noise.offset = match parts {
+            Ok(ref p) if p.len() == 2 => [p[0], p[1]],
+            _ => return error::invalid("Offset must be given as x,y")
+        };
+    }
+    if let Some(v) = _parse(matches, "scale", "Scale")? { noise.scale = v; }
+    if let Some(v) = _given(matches, "layers") {
+        noise.layers = v.split(',')
+            .map(|layer| {
+                let parts: Vec<&str> = layer.split(':').collect();
+                if parts.len() != 2 {
+                    return error::invalid(
+                        "Layers must be given as frequency:weight pairs");
+                }
+                Ok(Layer {
+                    frequency: parts[0].trim().parse::<u16>()
+                        .map_err(|_| Error::Invalid(String::from(
+                            "Layer frequencies must be integer values")))?,
+                    weight: parts[1].trim().parse::<f64>()
+                        .map_err(|_| Error::Invalid(String::from(
+                            "Layer weights must be numbers")))?
+                })
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    let field = &mut scene.field;
+    if let Some(v) = _owned(matches, "field") { field.kind = v; }
+    if let Some(v) = _owned(matches, "field image") { field.image = Some(v); }
+    if let Some(v) = _parse(matches, "noise mix", "Noise mix")? { field.noise_mix = v; }
+    if let Some(v) = _parse(matches, "phase", "Phase")? { field.phase = Some(v); }
+    if let Some(v) = _parse(matches, "field resolution", "Field resolution")? {
+        field.resolution = Some(v);
+    }
+    if let Some(v) = _owned(matches, "field aspect") { field.aspect = v; }
+    if let Some(v) = _switch(matches, "interpolate") { field.interpolate = v; }
+    if let Some(v) = _owned(matches, "load field") { field.load = Some(v); }
+    if let Some(v) = _owned(matches, "save field") { field.save = Some(v); }
+    if let Some(v) = _owned(matches, "path") { field.save = Some(v); }
+
+    let seeding = &mut scene.seeding;
+    if let Some(v) = _owned(matches, "seeding") { seeding.strategy = v; }
+    if let Some(v) = _parse(matches, "particles", "Number of particles")? {
+        seeding.particles = Some(v);
+    }
+    if let Some(v) = _owned(matches, "seed image") {
+        // a seed image on its own is enough to ask for density seeding
+        if _given(matches, "seeding").is_none() {
+            seeding.strategy = String::from("density");
         }
-        
-    }    
+        seeding.image = Some(v);
+    }
+
+    let particles = &mut scene.particles;
+    if let Some(v) = _owned(matches, "boundary") { particles.boundary = v; }
+    if let Some(v) = _given(matches, "emitter") {
+        particles.emitter = v.split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| Error::Invalid(
+                String::from("Emitter must be a comma-separated list \
+                              of numbers"))))
+            .collect::<Result<_>>()?;
+    }
+    if let Some(v) = _parse(matches, "lifetime", "Lifetime")? { particles.lifetime = v; }
+    if let Some(v) = _parse(matches, "lifetime spread", "Lifetime spread")? {
+        particles.lifetime_spread = v;
+    }
+    if let Some(v) = _parse(matches, "fade", "Fade")? { particles.fade = v; }
+    if let Some(v) = _owned(matches, "mask") { particles.mask = Some(v); }
+
+    let integrator = &mut scene.integrator;
+    if let Some(v) = _parse(matches, "dt", "Time step")? { integrator.dt = v; }
+    if let Some(v) = _parse(matches, "steps", "Steps")? { integrator.steps = v; }
+    if let Some(v) = _parse(matches, "friction", "Friction")? { integrator.friction = v; }
+
+    let world = &mut scene.world;
+    if let Some(v) = _owned(matches, "units") { world.units = v; }
+    if let Some(v) = _parse(matches, "density", "Density")? { world.density = v; }
+    if let Some(v) = _parse(matches, "stroke width", "Stroke width")? {
+        world.stroke_width = v;
+    }
+
+    let post = &mut scene.post;
+    if let Some(v) = _parse(matches, "blur", "Blur")? { post.blur = v; }
+    if let Some(v) = _parse(matches, "bloom", "Bloom")? { post.bloom = v; }
+    if let Some(v) = _parse(matches, "bloom threshold", "Bloom threshold")? {
+        post.bloom_threshold = v;
+    }
+    if let Some(v) = _parse(matches, "bloom radius", "Bloom radius")? {
+        post.bloom_radius = v;
+    }
+    if let Some(v) = _parse(matches, "sharpen", "Sharpen")? { post.sharpen = v; }
+    if let Some(v) = _parse(matches, "sharpen radius", "Sharpen radius")? {
+        post.sharpen_radius = v;
+    }
+    if let Some(v) = _parse(matches, "vignette", "Vignette")? { post.vignette = v; }
+
+    let tone = &mut scene.tone;
+    if let Some(v) = _parse(matches, "exposure", "Exposure")? { tone.exposure = v; }
+    if let Some(v) = _parse(matches, "gamma", "Gamma")? { tone.gamma = v; }
+
+    // `color function` has a default, so ask whether -c itself was given
+    // rather than relying on clap to see it as absent
+    if matches.occurrences_of("color function") > 0
+        && (matches.is_present("stroke color")
+            || matches.is_present("color image")) {
+        return error::invalid("--color can't be combined with \
+                               --stroke-color or --color-image");
+    }
 
-    // Save the image as “fractal.png”
-    let fout = &mut File::create(name).unwrap();
+    let color = &mut scene.color;
+    if let Some(v) = _parse(matches, "color function", "Color function")? {
+        color.function = v;
+    }
+    if let Some(v) = _owned(matches, "stroke color") { color.stroke = Some(v); }
+    if let Some(v) = _owned(matches, "color image") {
+        color.stroke = Some(String::from("image"));
+        color.image = Some(v);
+    }
+    if let Some(v) = _owned(matches, "color sample") { color.sample = v; }
+    if let Some(v) = _owned(matches, "blend") { color.blend = v; }
+    if let Some(v) = _owned(matches, "field color") { color.field = Some(v); }
+    if let Some(v) = _given(matches, "background") {
+        color.background = v.split(',').map(|c| String::from(c.trim())).collect();
+    }
 
-    // We must indicate the image's color type
-    // and what format to save as
-    image::ImageRgb8(imgbuf).save(fout, image::PNG).unwrap();
+    let animation = &mut scene.animation;
+    if let Some(v) = _parse(matches, "framerate", "Framerate")? {
+        animation.framerate = v;
+    }
+    if let Some(v) = _owned(matches, "ffmpeg") { animation.ffmpeg = v; }
 
+    return Ok(());
 }
 
-fn write_image_1ch(data: &[f64],
-                   img_width: usize, img_height: usize,
-                   name: &Path) {
+// Tiles existing renders into a contact sheet, captioned with the
+// parameters recorded in their metadata
+fn make_sheet(matches: &ArgMatches) -> Result<()> {
+    let keys: Vec<&str> = matches.value_of("caption").unwrap()
+        .split(',')
+        .map(|k| k.trim())
+        .filter(|k| !k.is_empty())
+        .collect();
+
+    let tiles: Vec<sheet::Tile> = matches.values_of("images").unwrap()
+        .map(|name| {
+            let path = Path::new(name);
+            let img = image::open(path)
+                .map_err(error::image(format!("Could not open {}", name)))?;
+
+            // images from elsewhere simply have no parameters to show
+            let meta = metadata::read_png_text(path).unwrap_or_default();
+
+            let mut caption = vec![path.file_name()
+                                   .map(|n| n.to_string_lossy().into_owned())
+                                   .unwrap_or_else(|| String::from(name))];
+            for key in &keys {
+                if let Some(value) = metadata::get(&meta, key) {
+                    caption.push(format!("{}={}", key, value));
+                }
+            }
 
-    let mut imgbuf = image::ImageBuffer::new(img_width as u32,
-                                             img_height as u32);
-    
-    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-        let val = data[(y*img_width as u32 + x) as usize];
+            Ok(sheet::Tile { image: img.to_rgb(), caption })
+        })
+        .collect::<Result<_>>()?;
 
-        *pixel = image::Rgb([(val * 255.0) as u8,
-                             (val * 255.0) as u8,
-                             (val * 255.0) as u8]);
-    }
-        
-    // Save the image as “fractal.png”
-    let fout = &mut File::create(name).unwrap();
+    let cols = match _parse(matches, "columns", "Columns")? {
+        Some(v) => v,
+        None => (tiles.len() as f64).sqrt().ceil() as usize
+    };
+
+    let thumb_width = _thumb_width(matches)?;
 
-    // We must indicate the image's color type
-    // and what format to save as
-    image::ImageRgb8(imgbuf).save(fout, image::PNG).unwrap();
+    let img = sheet::contact_sheet(&tiles, cols, thumb_width,
+                                   matches.value_of("title"))?;
+    return render::save_image(image::ImageRgb8(img),
+                              Path::new(&format!("{}.png",
+                                                 matches.value_of("output")
+                                                 .unwrap())),
+                              &[]);
+}
 
+fn _thumb_width(matches: &ArgMatches) -> Result<u32> {
+    return matches.value_of("thumb width").unwrap()
+        .parse::<u32>()
+        .map_err(|_| Error::Invalid(String::from(
+            "Thumbnail width must be an integer value")));
 }
 
-fn make_tracers(d1: &[f64],
-                perlin_size: usize,
-                img_width: usize,
-                img_height: usize,
-                phase: f64,
-                num_jobs: usize) -> Vec<Tracer> {
-    
-    let mut tracers = Vec::new();
-    for _ in 0..num_jobs {
-        tracers.push(Tracer::new(d1,
-                                 perlin_size,
-                                 img_width, img_height,
-                                 phase));
-    }
-    
-    let mut c = 0;
-    for x in 0..img_width {
-        for y in 0..img_height {
-            let part_x = x as f64 / img_width as f64;
-            let part_y = y as f64 / img_height as f64;
-
-            tracers[c].add_particle(part_x, part_y);
-            c = (c + 1) % tracers.len();
+// Re-renders an image from the scene recorded in its PNG metadata
+fn reproduce(matches: &ArgMatches) -> Result<()> {
+    let name = matches.value_of("image").unwrap();
+    let path = Path::new(name);
+    let meta = metadata::read_png_text(path)
+        .map_err(error::io(format!("Could not read {}", name)))?;
+
+    let text = match metadata::get(&meta, "scene") {
+        Some(text) => text,
+        None => return error::invalid(format!(
+            "{} does not record the scene it was made from", name))
+    };
+    let mut scene = Scene::from_toml(text)?;
+
+    if let Some(version) = metadata::get(&meta, "version") {
+        if version != env!("CARGO_PKG_VERSION") {
+            eprintln!("Warning: the image was made by perlin {}, this is {}; \
+                       the result may differ", version,
+                      env!("CARGO_PKG_VERSION"));
         }
     }
 
-    return tracers;
-}
+    scene.output.name = match matches.value_of("output") {
+        Some(name) => String::from(name),
+        None => format!("{}-reproduced",
+                        path.with_extension("").to_string_lossy())
+    };
+    scene.output.format = String::from("png");
+    scene.output.dump_config = false;
+    scene.field.save = None;
 
-fn make_octaves(img_width: usize, img_height: usize) -> Vec<f64> {
-    let data = vec![perlin(2, img_width),
-                    perlin(4, img_width),
-                    perlin(8, img_width)];
+    render::render(&scene)?;
+    return Ok(());
+}
 
-    let mut to_r = Vec::new();
+// Options every rendering subcommand shares
+fn scene_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("config")
+            .long("config")
+            .help("Scene file (TOML, or JSON with a .json extension) to start from; other flags override it")
+            .takes_value(true),
+        Arg::with_name("dump config")
+            .long("dump-config")
+            .help("Write the effective scene next to the output as <output>.toml")
+            .takes_value(false),
+        Arg::with_name("no dump config")
+            .long("no-dump-config")
+            .help("Don't write the effective scene, even if the scene file asks for it")
+            .takes_value(false)
+            .conflicts_with("dump config"),
+        Arg::with_name("seed")
+            .long("seed")
+            .help("Seed for all of the randomness (a random one is picked and recorded when missing)")
+            .takes_value(true)
+    ];
+}
 
-    for idx in 0..(img_width*img_height) {
-        let d1 = (data[0][idx] * 2.0) - 1.0;
-        let d2 = (data[1][idx] * 2.0) - 1.0;
-        let d3 = (data[2][idx] * 2.0) - 1.0;
-        
-        to_r.push(d1 * 0.10
-                  + d2 * 0.20
-                  + d3 * 0.40);
-    }
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    return Arg::with_name("format")
+        .long("format")
+        .help("Image format of the output")
+        .takes_value(true)
+        .possible_values(&["png", "jpeg", "bmp", "ppm"])
+        .default_value("png");
+}
 
-    // normalize
-    let max_val = to_r.iter()
-        .fold(f64::NEG_INFINITY, |a, &b| a.max(b));
+// Where and how the image is written
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .help("Name of output file")
+            .takes_value(true)
+            .default_value("out"),
+        format_arg()
+    ];
+}
 
-    let min_val = to_r.iter()
-        .fold(f64::INFINITY, |a, &b| a.min(b));
+// Size of a flow field render
+fn canvas_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("width")
+            .short("w")
+            .long("width")
+            .help("Width of the output image")
+            .takes_value(true)
+            .default_value("960"),
+        Arg::with_name("height")
+            .short("H")
+            .long("height")
+            .help("Height of the output image")
+            .takes_value(true)
+            .default_value("540"),
+        Arg::with_name("ssaa")
+            .long("ssaa")
+            .help("Trace at this many times the output resolution and filter the result down, to smooth fine strokes")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::with_name("filter")
+            .long("filter")
+            .help("Filter that brings a supersampled render down to the output size")
+            .takes_value(true)
+            .possible_values(&["box", "lanczos", "mitchell"])
+            .default_value("mitchell")
+    ];
+}
 
-    let range = max_val - min_val;
+// The Perlin noise behind a single layer or a flow field
+fn noise_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("frequency")
+            .long("noise-frequency")
+            .short("f")
+            .help("Frequency of Perlin noise")
+            .takes_value(true)
+            .default_value("3")
+    ];
+    args.extend(tileable_args());
+    return args;
+}
 
-    return to_r.iter().map(|v| (v - min_val) / range)
-        .collect();
+// Shared by the single-layer noise and fbm
+fn tileable_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("tileable")
+            .long("tileable")
+            .help("Make the noise wrap around at the edges")
+            .takes_value(false),
+        Arg::with_name("no tileable")
+            .long("no-tileable")
+            .help("Don't wrap the noise, even if the scene file does")
+            .takes_value(false)
+            .conflicts_with("tileable")
+    ];
+}
 
+// Which part of the (unbounded) noise is shown
+fn window_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("offset")
+            .long("offset")
+            .help("Top left corner of the noise shown, as x,y (1 is the width of the default view, so 1,0 is the tile to its right)")
+            .takes_value(true)
+            .allow_hyphen_values(true),
+        Arg::with_name("scale")
+            .long("scale")
+            .help("Length of the noise shown along the shorter side, relative to the default view (2 zooms out)")
+            .takes_value(true)
+    ];
 }
 
-fn write_tracers(out_name: &Path,
-                 img_width: usize,
-                 img_height: usize,
-                 tracers: &[Tracer],
-                 max_flux: usize,
-                 color_func: u8) {
-    
-    let mut init_val: Vec<usize> = Vec::new();
-    for _ in 0..img_width {
-        for _ in 0..img_height {
-            init_val.push(0);
-        }
-    }
-    
-    let data = tracers.iter()
-        .map(|t| t.get_unnormalized_flux());
+// How the noise (or an image) becomes a flow field
+fn field_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("field")
+            .long("field")
+            .help("How the noise drives the particles")
+            .takes_value(true)
+            .possible_values(&["angle", "curl", "image-angle",
+                               "image-gradient", "image-edges"])
+            .default_value("angle"),
+        Arg::with_name("field image")
+            .long("field-image")
+            .help("Grayscale image for the image-* fields: its brightness is an angle, or its gradient (or edges) the flow")
+            .takes_value(true),
+        Arg::with_name("noise mix")
+            .long("noise-mix")
+            .help("How much Perlin noise to blend into an image field (0 to 1)")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("phase")
+            .long("phase")
+            .help("Rotation of the field's angles in radians (random by default)")
+            .takes_value(true),
+        Arg::with_name("field resolution")
+            .long("field-resolution")
+            .help("Cells of the flow field along the canvas' shorter side (half its pixels by default)")
+            .takes_value(true),
+        Arg::with_name("field aspect")
+            .long("field-aspect")
+            .help("Stretch one square of noise over the canvas, or give the field the canvas' aspect ratio")
+            .takes_value(true)
+            .possible_values(&["square", "canvas"])
+            .default_value("square"),
+        Arg::with_name("interpolate")
+            .long("interpolate")
+            .help("Blend the flow field between its cells instead of using the nearest one")
+            .takes_value(false),
+        Arg::with_name("no interpolate")
+            .long("no-interpolate")
+            .help("Use the nearest field cell, even if the scene file interpolates")
+            .takes_value(false)
+            .conflicts_with("interpolate")
+    ];
+}
 
-    for vec in data {
-        for c in 0..vec.len() {
-            init_val[c] += vec[c];
-        }
-    }
-    
-    let res:Vec<f64> = init_val.iter()
-        .map(|&k| k as f64 / max_flux as f64)
-        .collect();
-    
-    write_image(&res,
-                img_width, img_height,
-                out_name, color_func);
+// Everything about the particles and their integration
+fn flow_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("load field")
+            .long("load-field")
+            .help("Render a saved flow field, a 16-bit grayscale PNG or an .npy array instead of generating one")
+            .takes_value(true),
+        Arg::with_name("save field")
+            .long("save-field")
+            .help("Save the flow field (.npy and .png export the final angles, anything else the native format)")
+            .takes_value(true),
+        Arg::with_name("seeding")
+            .long("seeding")
+            .help("How the initial particles are placed")
+            .takes_value(true)
+            .possible_values(&["grid", "random", "jitter", "poisson",
+                               "halton", "sobol", "density"])
+            .default_value("grid"),
+        Arg::with_name("particles")
+            .long("particles")
+            .help("Number of particles to seed (defaults to one per pixel; \
+                   grid seeding rounds it to a full grid)")
+            .takes_value(true),
+        Arg::with_name("seed image")
+            .long("seed-image")
+            .help("Grayscale image whose brightness weights where particles spawn (implies density seeding)")
+            .takes_value(true),
+        Arg::with_name("boundary")
+            .long("boundary")
+            .help("What happens to particles that leave the canvas")
+            .takes_value(true)
+            .possible_values(&["discard", "wrap", "reflect",
+                               "respawn", "emitter", "clamp"])
+            .default_value("discard"),
+        Arg::with_name("emitter")
+            .long("emitter")
+            .help("Respawn point for the emitter boundary, as x,y[,radius] in [0, 1]")
+            .takes_value(true)
+            .default_value("0.5,0.5,0.02"),
+        Arg::with_name("lifetime")
+            .long("lifetime")
+            .help("Maximum particle age in steps before it respawns (0 to live forever)")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("lifetime spread")
+            .long("lifetime-spread")
+            .help("Fraction by which each particle's lifetime is randomized (0 to 1)")
+            .takes_value(true)
+            .default_value("0.5"),
+        Arg::with_name("fade")
+            .long("fade")
+            .help("Fraction of a particle's lifetime spent fading in and out (0 to 0.5)")
+            .takes_value(true)
+            .default_value("0.2"),
+        Arg::with_name("mask")
+            .long("mask")
+            .help("Grayscale image whose brightness gates where particles leave trails")
+            .takes_value(true),
+        Arg::with_name("dt")
+            .long("dt")
+            .help("Integration time step")
+            .takes_value(true)
+            .default_value("0.0025"),
+        Arg::with_name("steps")
+            .long("steps")
+            .help("Number of integration steps (and animation frames)")
+            .takes_value(true)
+            .default_value("900"),
+        Arg::with_name("friction")
+            .long("friction")
+            .help("Fraction of a particle's velocity lost per unit of time")
+            .takes_value(true)
+            .default_value("0.5"),
+        Arg::with_name("units")
+            .long("units")
+            .help("Measure the particle count and stroke width in output pixels, or relative to the canvas so previews match full-size renders")
+            .takes_value(true)
+            .possible_values(&["pixels", "canvas"])
+            .default_value("pixels"),
+        Arg::with_name("density")
+            .long("density")
+            .help("With canvas units, particles per square of the canvas' shorter side (when --particles isn't given)")
+            .takes_value(true)
+            .default_value("250000"),
+        Arg::with_name("stroke width")
+            .long("stroke-width")
+            .help("With canvas units, width of the strokes as a fraction of the canvas' shorter side")
+            .takes_value(true)
+            .default_value("0.002"),
+        Arg::with_name("number of jobs")
+            .short("j")
+            .long("jobs")
+            .help("Number of parallel jobs")
+            .default_value("1")
+    ];
 }
 
+// How the particle traces are colored
+fn color_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("color function")
+            .short("c")
+            .long("color")
+            .help("The coloring function to use (0 through 3)")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("stroke color")
+            .long("stroke-color")
+            .help("Color each stroke individually instead of using a color function")
+            .takes_value(true)
+            .possible_values(&["position", "speed", "heading", "age"]),
+        Arg::with_name("color image")
+            .long("color-image")
+            .help("Image the strokes take their colors from")
+            .takes_value(true)
+            .conflicts_with("stroke color"),
+        Arg::with_name("color sample")
+            .long("color-sample")
+            .help("Where strokes sample the color image")
+            .takes_value(true)
+            .possible_values(&["spawn", "current"])
+            .default_value("current"),
+        Arg::with_name("blend")
+            .long("blend")
+            .help("How overlapping stroke colors combine")
+            .takes_value(true)
+            .possible_values(&["additive", "average"])
+            .default_value("average"),
+        Arg::with_name("field color")
+            .long("field-color")
+            .help("Color by the mean particle motion through each pixel instead of density")
+            .takes_value(true)
+            .possible_values(&["heading", "speed", "curvature",
+                               "heading-speed"])
+            .conflicts_with_all(&["stroke color", "color image"]),
+        Arg::with_name("exposure")
+            .long("exposure")
+            .help("Brightness multiplier applied before the tone curve")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::with_name("gamma")
+            .long("gamma")
+            .help("Exponent of the tone curve")
+            .takes_value(true)
+            .default_value("0.5"),
+        Arg::with_name("background")
+            .long("background")
+            .help("Background color as #rrggbb, or two of them (top,bottom) for a gradient")
+            .takes_value(true),
+        Arg::with_name("transparent")
+            .long("transparent")
+            .help("Write an RGBA PNG whose alpha channel is the flux, to composite over other artwork")
+            .takes_value(false)
+            .conflicts_with("background"),
+        Arg::with_name("no transparent")
+            .long("no-transparent")
+            .help("Write an opaque image, even if the scene file asks for transparency")
+            .takes_value(false)
+            .conflicts_with("transparent")
+    ];
+}
 
+// Effects on the accumulated image
+fn post_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("blur")
+            .long("blur")
+            .help("Gaussian blur, as a fraction of the canvas' shorter side")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("bloom")
+            .long("bloom")
+            .help("Strength of the glow around bright strokes")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("bloom threshold")
+            .long("bloom-threshold")
+            .help("Brightness (a fraction of the brightest pixel) above which strokes glow")
+            .takes_value(true)
+            .default_value("0.5"),
+        Arg::with_name("bloom radius")
+            .long("bloom-radius")
+            .help("Size of the glow, as a fraction of the canvas' shorter side")
+            .takes_value(true)
+            .default_value("0.01"),
+        Arg::with_name("sharpen")
+            .long("sharpen")
+            .help("Strength of an unsharp mask")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("sharpen radius")
+            .long("sharpen-radius")
+            .help("Size of the unsharp mask's blur, as a fraction of the canvas' shorter side")
+            .takes_value(true)
+            .default_value("0.005"),
+        Arg::with_name("vignette")
+            .long("vignette")
+            .help("How much darker the corners get (0 to 1)")
+            .takes_value(true)
+            .default_value("0")
+    ];
+}
 
+// Turning the frames into a video
+fn animation_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("framerate")
+            .long("framerate")
+            .help("Frames per second of the animation")
+            .takes_value(true)
+            .default_value("60"),
+        Arg::with_name("ffmpeg")
+            .long("ffmpeg")
+            .help("The ffmpeg binary that assembles the frames")
+            .takes_value(true)
+            .default_value("/usr/bin/ffmpeg")
+    ];
+}
 
-fn main() {
+// Size and layers of a plain noise image
+fn noise_image_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    return vec![
+        Arg::with_name("width")
+            .short("w")
+            .long("width")
+            .help("Width of the noise image")
+            .takes_value(true)
+            .default_value("960"),
+        Arg::with_name("height")
+            .short("H")
+            .long("height")
+            .help("Height of the noise image")
+            .takes_value(true)
+            .default_value("540")
+    ];
+}
 
-    let matches = App::new("Perlin flow fields")
+// Every subcommand and its options
+fn app<'a, 'b>() -> App<'a, 'b> {
+    return App::new("Perlin flow fields")
         .version("0.0.1")
         .author("Ryan Marcus <ryan@ryanmarc.us>")
         .about("A program to make (optionally animated) Perlin flow fields")
-        .arg(Arg::with_name("output")
-             .short("o")
-             .help("Name of output file")
-             .takes_value(true)
-             .default_value("out"))
-        .arg(Arg::with_name("width")
-             .short("w")
-             .help("Width of the output image")
-             .takes_value(true)
-             .default_value("960"))
-        .arg(Arg::with_name("height")
-             .short("h")
-             .help("height of the output image")
-             .takes_value(true)
-             .default_value("540"))
-        .arg(Arg::with_name("frequency")
-             .long("noise-frequency")
-             .short("f")
-             .help("Frequency of Perlin noise")
-             .takes_value(true)
-             .default_value("3"))
-        .arg(Arg::with_name("animate")
-             .short("a")
-             .help("Create an animation (instead of a single frame)")
-             .takes_value(false))
-        .arg(Arg::with_name("color function")
-             .short("c")
-             .help("The coloring function to use (0 through 3)")
-             .takes_value(true)
-             .default_value("0"))
-        .arg(Arg::with_name("number of jobs")
-             .short("j")
-             .long("jobs")
-             .help("Number of parallel jobs")
-             .default_value("1"))
-        .arg(Arg::with_name("perlin")
-             .short("p")
-             .long("perlin")
-             .help("Just produce Perlin noise with width size")
-             .conflicts_with("color function")
-             .conflicts_with("animate")
-             .conflicts_with("number of jobs")
-             .conflicts_with("height"))
-        .arg(Arg::with_name("octaves")
-             .long("octaves")
-             .help("Produce Perlin noise with multiple pre-defined octaves")
-             .conflicts_with("color function")
-             .conflicts_with("animate")
-             .conflicts_with("number of jobs")
-             .conflicts_with("height")
-             .conflicts_with("perlin"))
-        .get_matches();
-
-    let out_name = matches.value_of("output").unwrap();
-    let color_func = matches.value_of("color function").unwrap()
-        .parse::<u8>()
-        .expect("Color function must be an integer value!");
-
-    let img_width = matches.value_of("width").unwrap().parse::<usize>()
-        .expect("Image width must be an integer value");
-
-    let img_height = matches.value_of("height").unwrap().parse::<usize>()
-        .expect("Image height must be an integer value");
-
-    let freq = matches.value_of("frequency").unwrap().parse::<u16>()
-        .expect("Frequency must be an integer value");
-
-    let do_animation = matches.is_present("animate");
-    let just_perlin = matches.is_present("perlin");
-    let octaves = matches.is_present("octaves");
-
-    let num_jobs = matches.value_of("number of jobs")
-        .unwrap().parse::<usize>()
-        .expect("Number of jobs must be an integer value");
-    
-    let perlin_size: usize = if just_perlin {
-        img_width
-    } else {
-        cmp::min(img_width, img_height)/2
-    };
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("noise")
+                    .about("Produce plain Perlin noise")
+                    .args(&scene_args())
+                    .args(&output_args())
+                    .args(&noise_image_args())
+                    .args(&noise_args())
+                    .args(&window_args()))
+        .subcommand(SubCommand::with_name("fbm")
+                    .about("Produce Perlin noise with several octaves")
+                    .args(&scene_args())
+                    .args(&output_args())
+                    .args(&noise_image_args())
+                    .arg(Arg::with_name("layers")
+                         .long("layers")
+                         .help("Octaves as comma-separated frequency:weight pairs")
+                         .takes_value(true)
+                         .default_value("2:0.1,4:0.2,8:0.4"))
+                    .args(&tileable_args())
+                    .args(&window_args()))
+        .subcommand(SubCommand::with_name("flow")
+                    .about("Render a single frame of particles moving through a flow field")
+                    .args(&scene_args())
+                    .args(&output_args())
+                    .args(&canvas_args())
+                    .args(&noise_args())
+                    .args(&window_args())
+                    .args(&field_args())
+                    .args(&flow_args())
+                    .args(&color_args())
+                    .args(&post_args()))
+        .subcommand(SubCommand::with_name("animate")
+                    .about("Render the particles frame by frame into a video")
+                    .args(&scene_args())
+                    .args(&output_args())
+                    .args(&canvas_args())
+                    .args(&noise_args())
+                    .args(&window_args())
+                    .args(&field_args())
+                    .args(&flow_args())
+                    .args(&color_args())
+                    .args(&post_args())
+                    .args(&animation_args()))
+        .subcommand(SubCommand::with_name("batch")
+                    .about("Render every combination of swept parameters in parallel, plus an index image")
+                    .args(&scene_args())
+                    .arg(Arg::with_name("sweep")
+                         .long("sweep")
+                         .help("A parameter (seed, frequency, colormap, dt or phase) and its values, e.g. seed=1..8 or dt=0.001..0.004:0.001 or colormap=0,3; repeat for a cartesian product")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .required(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .help("Output names, where {index} and {<param>} are replaced by each render's values; names without placeholders (including the scene's own, when this isn't given) get the index appended")
+                         .takes_value(true))
+                    .arg(format_arg())
+                    .arg(Arg::with_name("index")
+                         .long("index")
+                         .help("Name of the labeled contact sheet of all the renders")
+                         .takes_value(true)
+                         .default_value("index"))
+                    .arg(Arg::with_name("thumb width")
+                         .long("thumb-width")
+                         .help("Width of each render in the index image")
+                         .takes_value(true)
+                         .default_value("240"))
+                    .args(&canvas_args())
+                    .args(&noise_args())
+                    .args(&window_args())
+                    .args(&field_args())
+                    .args(&flow_args())
+                    .args(&color_args())
+                    .args(&post_args()))
+        .subcommand(SubCommand::with_name("field")
+                    .about("Generate a flow field and save it without rendering")
+                    .args(&scene_args())
+                    .arg(Arg::with_name("path")
+                         .help("Where to save the field (.npy and .png export the final angles, anything else the native format)")
+                         .required(true))
+                    .args(&canvas_args())
+                    .args(&noise_args())
+                    .args(&window_args())
+                    .args(&field_args()))
+        .subcommand(SubCommand::with_name("sheet")
+                    .about("Lay existing renders out in a labeled contact sheet")
+                    .arg(Arg::with_name("images")
+                         .help("Images to include, in order")
+                         .required(true)
+                         .multiple(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .help("Name of output file")
+                         .takes_value(true)
+                         .default_value("sheet"))
+                    .arg(Arg::with_name("caption")
+                         .long("caption")
+                         .help("Comma-separated metadata keys to caption each image with")
+                         .takes_value(true)
+                         .default_value("seed,noise,frequency,colormap"))
+                    .arg(Arg::with_name("title")
+                         .long("title")
+                         .help("Line of text across the top of the sheet")
+                         .takes_value(true))
+                    .arg(Arg::with_name("columns")
+                         .long("columns")
+                         .help("Images per row (defaults to a square-ish grid)")
+                         .takes_value(true))
+                    .arg(Arg::with_name("thumb width")
+                         .long("thumb-width")
+                         .help("Width of each image in the sheet")
+                         .takes_value(true)
+                         .default_value("240")))
+        .subcommand(SubCommand::with_name("reproduce")
+                    .about("Re-render an image from the parameters recorded in its PNG metadata")
+                    .arg(Arg::with_name("image")
+                         .help("PNG written by perlin")
+                         .required(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .help("Name of output file (defaults to <image>-reproduced)")
+                         .takes_value(true)));
+}
 
-    if octaves {
-        let octave_img:Vec<f64> = make_octaves(img_width, img_width);
-        write_image_1ch(&octave_img, img_width, img_width,
-                        Path::new(&format!("{}.png", out_name)));
-        return;
-    }
-    
-    let d1 = perlin(freq, perlin_size);
-
-    if just_perlin {
-        write_image_1ch(&d1, img_width, img_width,
-                        Path::new(&format!("{}.png", out_name)));
-        return;
-    }
-    
-    let btwn = Range::new(0.0,
-                          2.0 * std::f64::consts::PI);
-    let mut rng = rand::thread_rng();
-    let phase = btwn.ind_sample(&mut rng);
-
-
-    let mut tracers = make_tracers(&d1, perlin_size,
-                                   img_width, img_height,
-                                   phase, num_jobs);
-
-
-    // first, progress all the way to the end to get the max flux value
-    tracers.par_iter_mut()
-        .for_each(|t| t.progress_for(15*60, 0.0025));
-
-    
-    // next, compute the max flux...
-    let max_flux = {
-        
-        let mut max = 0;
-        let data: Vec<&Vec<usize>> = tracers.iter()
-            .map(|t| t.get_unnormalized_flux())
-            .collect();
-
-        for idx in 0..data[0].len() {
-            let mut sum = 0;
-            for d in &data {
-                sum += d[idx];
-            }
+fn main() {
+    let matches = app().get_matches();
 
-            max = cmp::max(max, sum);
-        }
+    let (command, sub) = matches.subcommand();
+
+    if let Err(e) = run(command, sub.unwrap()) {
+        eprintln!("error: {}", e);
+        process::exit(e.exit_code());
+    }
+}
 
-        max
+// The scene file (if any) with the command line on top
+fn scene_of(command: &str, sub: &ArgMatches) -> Result<Scene> {
+    let mut scene = match sub.value_of("config") {
+        Some(path) => Scene::load(Path::new(path))?,
+        None => Scene::default()
     };
 
-    if !do_animation {
-        // write the normalized flux out, and we're done.
-        write_tracers(Path::new(&format!("{}.png", out_name)),
-                      img_width, img_height,
-                      &tracers,
-                      max_flux, color_func);
-        return;
+    // the subcommand decides what gets made, whatever the scene file says
+    scene.mode = String::from(match command {
+        "fbm" => "octaves",
+        "animate" | "batch" => "flow",
+        other => other
+    });
+    scene.animation.enabled = command == "animate";
+
+    apply_args(&mut scene, sub)?;
+    return Ok(scene);
+}
+
+fn run(command: &str, sub: &ArgMatches) -> Result<()> {
+    if command == "reproduce" {
+        return reproduce(sub);
+    }
+
+    if command == "sheet" {
+        return make_sheet(sub);
+    }
+
+    let mut scene = scene_of(command, sub)?;
+
+    if command == "field" {
+        // a dumped scene goes next to the field
+        let path = Path::new(sub.value_of("path").unwrap());
+        scene.output.name = path.with_extension("")
+            .to_string_lossy().into_owned();
+    }
+
+    if command == "batch" {
+        let sweeps: Vec<batch::Sweep> = sub.values_of("sweep").unwrap()
+            .map(batch::parse_sweep)
+            .collect::<Result<_>>()?;
+        let jobs = batch::expand(&scene, &sweeps)?;
+
+        // with several sweeps, each row of the sheet holds the last one
+        let cols = if sweeps.len() > 1 {
+            sweeps[sweeps.len() - 1].values.len()
+        } else {
+            (jobs.len() as f64).sqrt().ceil() as usize
+        };
+
+        let thumb_width = _thumb_width(sub)?;
+
+        return batch::run(&jobs, &sweeps, sub.value_of("index").unwrap(),
+                          cols, thumb_width);
     }
 
+    render::render(&scene)?;
+    return Ok(());
+}
 
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use super::{app, scene_of};
+    use scene::Scene;
+    use render;
+
+    // Parses a command line and builds its scene the way `run` does
+    fn _scene(args: &[&str]) -> Scene {
+        let matches = app().get_matches_from(args);
+        let (command, sub) = matches.subcommand();
+        return scene_of(command, sub.unwrap()).unwrap();
+    }
 
-    // now, reset / remake all the tracers and generate the frames
-    
-    tracers = make_tracers(&d1, perlin_size,
-                           img_width, img_height,
-                           phase, num_jobs);
+    #[test]
+    fn flags_override_the_scene_file_but_defaults_dont() {
+        let path = env::temp_dir().join("perlin-main-override.toml");
+        let mut file = Scene::default();
+        file.output.width = 123;
+        file.output.dump_config = true;
+        file.noise.frequency = 7;
+        file.noise.tileable = true;
+        file.field.interpolate = true;
+        file.output.transparent = true;
+        file.save(&path).unwrap();
+        let config = path.to_str().unwrap();
+
+        // -w and -f have defaults of their own, which mustn't win
+        let scene = _scene(&["perlin", "flow", "--config", config]);
+        assert_eq!(scene.output.width, 123);
+        assert_eq!(scene.noise.frequency, 7);
+        assert!(scene.noise.tileable && scene.field.interpolate
+                && scene.output.transparent && scene.output.dump_config);
+
+        let scene = _scene(&["perlin", "flow", "--config", config,
+                             "-w", "50", "-f", "2", "--no-tileable",
+                             "--no-interpolate", "--no-transparent",
+                             "--no-dump-config"]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(scene.output.width, 50);
+        assert_eq!(scene.noise.frequency, 2);
+        assert!(!scene.noise.tileable && !scene.field.interpolate
+                && !scene.output.transparent && !scene.output.dump_config);
+    }
 
-    
-    let temp_dir = Temp::new_dir().unwrap();
+    #[test]
+    fn dumped_scenes_load_back_the_same() {
+        let name = env::temp_dir().join("perlin-main-dump");
+        let name = name.to_str().unwrap();
+        let scene = _scene(&["perlin", "noise", "-w", "8", "-f", "2",
+                             "--seed", "3", "--offset", "1,0.5",
+                             "--tileable", "--dump-config", "-o", name]);
+        render::render(&scene).unwrap();
+
+        let dump = format!("{}.toml", name);
+        let back = Scene::load(Path::new(&dump)).unwrap();
+        fs::remove_file(&dump).unwrap();
+        fs::remove_file(format!("{}.png", name)).unwrap();
+        assert_eq!(back.to_toml().unwrap(), scene.to_toml().unwrap());
+    }
 
+    // Each rendering subcommand on its own gives the default scene,
+    // apart from what it makes
+    #[test]
+    fn subcommands_map_to_their_modes() {
+        let cases: [(&[&str], &str); 6] = [
+            (&["perlin", "noise"], "noise"),
+            (&["perlin", "fbm"], "octaves"),
+            (&["perlin", "flow"], "flow"),
+            (&["perlin", "animate"], "flow"),
+            (&["perlin", "batch", "--sweep", "seed=1..2"], "flow"),
+            (&["perlin", "field", "out.npy"], "field")
+        ];
+
+        for &(args, mode) in cases.iter() {
+            let mut expected = Scene {
+                mode: String::from(mode),
+                ..Scene::default()
+            };
+            expected.animation.enabled = args[1] == "animate";
+            if args[1] == "field" {
+                expected.field.save = Some(String::from("out.npy"));
+            }
 
-    for frame in 0..15*60 {
-        if frame % 10 == 0 {
-            println!("Computing frame {} / {}...", frame, 15*60);
+            assert_eq!(_scene(args).to_toml().unwrap(),
+                       expected.to_toml().unwrap(), "{}", args[1]);
         }
-        
-        tracers.par_iter_mut()
-            .for_each(|t| t.progress(0.0025));
-
-        let pb = temp_dir.as_ref()
-            .join(Path::new(&format!("out_{:04}.png",
-                                     frame)
-            ));
-        write_tracers(&pb,
-                      img_width, img_height,
-                      &tracers,
-                      max_flux, color_func);
-    }
-
-    // now, run the FFMPEG command
-    // /usr/bin/ffmpeg -framerate 60 -i out_%04d.png -pix_fmt yuv420p out.mp4
-    let mut child = Command::new("/usr/bin/ffmpeg")
-        .args(&["-framerate", "60", "-i",
-                &format!("{}/out_%04d.png", temp_dir.as_ref().to_str().unwrap()),
-                "-pix_fmt", "yuv420p",
-                &format!("{}.mp4", out_name)])
-        .spawn()
-        .expect("failed to execute process");
-    
-    child.wait().unwrap();
+    }
 
+    #[test]
+    fn sheet_and_reproduce_parse_their_arguments() {
+        let matches = app().get_matches_from(["perlin", "sheet", "a.png",
+                                              "b.png", "--columns", "2"]);
+        let sub = matches.subcommand_matches("sheet").unwrap();
+        assert_eq!(sub.values_of("images").unwrap().count(), 2);
+        assert_eq!(sub.value_of("output"), Some("sheet"));
+
+        let matches = app().get_matches_from(["perlin", "reproduce",
+                                              "out.png"]);
+        let sub = matches.subcommand_matches("reproduce").unwrap();
+        assert_eq!(sub.value_of("image"), Some("out.png"));
+        assert_eq!(sub.value_of("output"), None);
+    }
+
+    #[test]
+    fn images_can_come_from_the_scene_file() {
+        let path = env::temp_dir().join("perlin-main-images.toml");
+        let mut file = Scene::default();
+        file.seeding.image = Some(String::from("weights.png"));
+        file.field.image = Some(String::from("edges.png"));
+        file.save(&path).unwrap();
+
+        let scene = _scene(&["perlin", "flow", "--config",
+                             path.to_str().unwrap(), "--seeding", "density",
+                             "--field", "image-edges"]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(scene.seeding.strategy, "density");
+        assert_eq!(scene.field.kind, "image-edges");
+        assert!(render::validate(&scene).is_ok());
+    }
 }
@@ -0,0 +1,198 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read};
+use std::path::Path;
+use png;
+use png::HasParameters;
+
+// Key / value pairs stored in a PNG's tEXt (or iTXt) chunks
+pub type Metadata = Vec<(String, String)>;
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+fn _invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+// Writes 8-bit RGB (or, with alpha, RGBA) pixel data as a PNG, with
+// one text chunk per entry: tEXt for ASCII values, and iTXt (which
+// holds UTF-8) for anything else, such as non-ASCII file names
+pub fn write_png(path: &Path, width: usize, height: usize, alpha: bool,
+                 pixels: &[u8], meta: &[(String, String)]) -> io::Result<()> {
+    let (color, channels) = if alpha {
+        (png::ColorType::RGBA, 4)
+    } else {
+        (png::ColorType::RGB, 3)
+    };
+    debug_assert_eq!(pixels.len(), width * height * channels);
+
+    let w = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder.set(color).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+
+    for (key, value) in meta {
+        // keywords are 1 to 79 Latin-1 characters with no separator
+        if key.is_empty() || key.len() >= 80 || key.contains('\0') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("bad PNG text keyword {:?}",
+                                              key)));
+        }
+
+        let mut data = Vec::with_capacity(key.len() + 5 + value.len());
+        data.extend_from_slice(key.as_bytes());
+        data.push(0);
+        if value.is_ascii() {
+            data.extend_from_slice(value.as_bytes());
+            writer.write_chunk(*b"tEXt", &data)?;
+        } else {
+            // uncompressed, with empty language and translated keyword
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(value.as_bytes());
+            writer.write_chunk(*b"iTXt", &data)?;
+        }
+    }
+
+    writer.write_image_data(pixels)?;
+    return Ok(());
+}
+
+// Reads every tEXt and uncompressed iTXt chunk of a PNG, in file order
+pub fn read_png_text(path: &Path) -> io::Result<Metadata> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    if bytes.len() < 8 || bytes[0..8] != SIGNATURE {
+        return Err(_invalid("not a PNG file"));
+    }
+
+    let mut to_r = Vec::new();
+    let mut pos = 8;
+
+    // each chunk is a big-endian length, a type, the data and a CRC
+    while pos + 8 <= bytes.len() {
+        let len = ((bytes[pos] as usize) << 24)
+            | ((bytes[pos + 1] as usize) << 16)
+            | ((bytes[pos + 2] as usize) << 8)
+            | (bytes[pos + 3] as usize);
+        let kind = &bytes[pos + 4..pos + 8];
+        let start = pos + 8;
+
+        if start + len + 4 > bytes.len() {
+            return Err(_invalid("truncated PNG chunk"));
+        }
+
+        let data = &bytes[start..start + len];
+
+        if kind == b"IEND" {
+            break;
+        }
+
+        if kind == b"tEXt" {
+            if let Some(sep) = data.iter().position(|&b| b == 0) {
+                to_r.push((_latin1(&data[..sep]),
+                           _text(&data[sep + 1..])));
+            }
+        }
+
+        if kind == b"iTXt" {
+            if let Some(entry) = _itxt(data) {
+                to_r.push(entry);
+            }
+        }
+
+        pos = start + len + 4;
+    }
+
+    return Ok(to_r);
+}
+
+fn _latin1(data: &[u8]) -> String {
+    return data.iter().map(|&b| b as char).collect();
+}
+
+// tEXt is Latin-1, but some other tools put UTF-8 there anyway, so
+// accept either
+fn _text(data: &[u8]) -> String {
+    return match String::from_utf8(data.to_vec()) {
+        Ok(s) => s,
+        Err(_) => _latin1(data)
+    };
+}
+
+// The keyword and text of an iTXt chunk: the keyword, a compression
+// flag and method, then a language tag and a translated keyword that
+// both end in a separator, then the UTF-8 text. Compressed text is
+// skipped, since we never write it.
+fn _itxt(data: &[u8]) -> Option<(String, String)> {
+    let sep = data.iter().position(|&b| b == 0)?;
+    let key = _latin1(&data[..sep]);
+
+    let rest = data.get(sep + 1..)?;
+    if rest.len() < 2 || rest[0] != 0 {
+        return None;
+    }
+
+    let mut text = &rest[2..];
+    for _ in 0..2 {
+        let end = text.iter().position(|&b| b == 0)?;
+        text = &text[end + 1..];
+    }
+
+    return String::from_utf8(text.to_vec()).ok().map(|v| (key, v));
+}
+
+pub fn get<'a>(meta: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    return meta.iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::{write_png, read_png_text, get};
+
+    #[test]
+    fn text_round_trips_through_text_and_itxt_chunks() {
+        let path = env::temp_dir().join("perlin-metadata-test.png");
+        let meta = vec![(String::from("seed"), String::from("42")),
+                        (String::from("scene"),
+                         String::from("image = \"fl\u{f6}de-\u{6d41}.png\""))];
+
+        write_png(&path, 2, 1, false, &[0; 6], &meta).unwrap();
+        let back = read_png_text(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(get(&back, "seed"), Some("42"));
+        assert_eq!(get(&back, "scene"), Some(meta[1].1.as_str()));
+    }
+
+    #[test]
+    fn bad_keywords_are_errors() {
+        let path = env::temp_dir().join("perlin-metadata-bad-key.png");
+        let meta = vec![(String::new(), String::from("x"))];
+        assert!(write_png(&path, 1, 1, false, &[0; 3], &meta).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
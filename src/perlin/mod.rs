@@ -17,27 +17,88 @@
 // along with perlin.  If not, see <http://www.gnu.org/licenses/>.
 // 
 // < end copyright > 
-use rand;
+use std::cmp;
+use rand::Rng;
+use grid::Field2D;
+use error;
+use error::Result;
 
+// A dim x dim grid of cells has (dim + 1) x (dim + 1) lattice points,
+// stored row by row with one gradient index each. Those cover offsets
+// in [0, 1]; lattice points beyond them get hashed gradients (or, when
+// tiling, wrap around), so the noise can be sampled anywhere.
 struct PerlinNoise {
     dim: u16,
-    grid: Vec<u8>
+    grid: Vec<u8>,
+    tileable: bool,
+
+    // keys the hash, so different grids extend differently
+    key: u64
+}
+
+// The part of the noise plane a render shows, in the units of the
+// [0, 1] square the noise normally covers: the top left corner and the
+// length of the shorter side that is shown (the longer side keeps the
+// image's aspect ratio). Windows that share a noise and an edge line
+// up, so renders can be stitched together.
+#[derive(Clone, Copy)]
+pub struct Window {
+    pub x: f64,
+    pub y: f64,
+    pub size: f64
+}
+
+impl Window {
+    pub fn unit() -> Window {
+        return Window { x: 0.0, y: 0.0, size: 1.0 };
+    }
+
+    pub fn is_unit(&self) -> bool {
+        return self.x == 0.0 && self.y == 0.0 && self.size == 1.0;
+    }
+
+    // where pixel (px, py) of an image whose shorter side is `short`
+    // pixels long falls
+    fn at(&self, px: usize, py: usize, short: usize) -> (f64, f64) {
+        return (self.x + px as f64 / short as f64 * self.size,
+                self.y + py as f64 / short as f64 * self.size);
+    }
+}
+
+// splitmix64's finalizer
+fn _mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
 }
 
 impl PerlinNoise {
-    fn new(dim: u16) -> PerlinNoise {
+    fn new<R: Rng>(dim: u16, tileable: bool,
+                   rng: &mut R) -> Result<PerlinNoise> {
+        if dim == 0 {
+            return error::invalid("Noise frequency must be at least 1");
+        }
+
         let mut to_r = PerlinNoise {
-            dim: dim,
-            grid: Vec::new()
+            dim,
+            grid: Vec::new(),
+            tileable,
+            key: 0
         };
 
         for _ in 0..dim+1 {
             for _ in 0..dim+1 {
-                to_r.grid.push(rand::random::<(u8)>()  % 8);
+                to_r.grid.push(rng.gen::<u8>() % 8);
             }
         }
 
-        return to_r;
+        // derived from the grid rather than drawn from the rng, so the
+        // random stream (and everything generated after this) is the
+        // same as before the noise was unbounded
+        to_r.key = to_r.grid.iter()
+            .fold(u64::from(dim), |h, &g| _mix(h ^ u64::from(g)));
+
+        return Ok(to_r);
     }
 
     /*
@@ -75,41 +136,68 @@ impl PerlinNoise {
     }
 
     fn _fade(v: f64) -> f64 {
-        assert!(v <= 1.0);
-        assert!(v >= 0.0);
-        return 6.0*v.powf(5.0) - 15.0*v.powf(4.0) + 10.0*v.powf(3.0);
+        debug_assert!(v <= 1.0);
+        debug_assert!(v >= 0.0);
+        // rounding can push the polynomial just past 1 near v = 1
+        let f = 6.0*v.powf(5.0) - 15.0*v.powf(4.0) + 10.0*v.powf(3.0);
+        return f.clamp(0.0, 1.0);
     }
 
     fn _lerp(a: f64, b: f64, w: f64) -> f64 {
-        assert!(w >= 0.0);
-        assert!(w <= 1.0);
+        debug_assert!(w >= 0.0);
+        debug_assert!(w <= 1.0);
 
         return w*a + (1.0 - w)*b;
     }
 
 
-    fn sample(&self, ox: f64, oy: f64) -> f64 {
+    fn _fade_deriv(v: f64) -> f64 {
+        return 30.0*v.powf(4.0) - 60.0*v.powf(3.0) + 30.0*v.powf(2.0);
+    }
 
-        assert!(ox <= 1.0);
-        assert!(oy <= 1.0);
-        
-        let x: f64 = ox * f64::from(self.dim);
-        let y: f64 = oy * f64::from(self.dim);
-        let sx: usize = x as usize;
-        let sy: usize = y as usize;
+    // the gradient at lattice point (x, y)
+    fn _gradient(&self, x: i64, y: i64) -> (f64, f64) {
+        let dim = i64::from(self.dim);
+
+        // when tiling, the lattice repeats every dim points, so
+        // opposite edges of the noise match up
+        let (x, y) = if self.tileable {
+            (x.rem_euclid(dim), y.rem_euclid(dim))
+        } else {
+            (x, y)
+        };
+
+        if x < 0 || y < 0 || x > dim || y > dim {
+            let h = _mix(self.key ^ _mix(x as u64) ^ _mix(y as u64)
+                         .rotate_left(32));
+            return PerlinNoise::_to_vec((h % 8) as u8);
+        }
 
+        let stride = (dim + 1) as usize;
+        return PerlinNoise::_to_vec(self.grid[y as usize * stride
+                                              + x as usize]);
+    }
+
+    fn _corners(&self, sx: i64, sy: i64) -> [(f64, f64); 4] {
+        let (ex, ey) = (sx + 1, sy + 1);
 
         // c1 c4
         // c2 c3
+        return [
+            self._gradient(sx, sy),
+            self._gradient(sx, ey),
+            self._gradient(ex, ey),
+            self._gradient(ex, sy)
+        ];
+    }
+
+    fn sample(&self, ox: f64, oy: f64) -> f64 {
+        let x: f64 = ox * f64::from(self.dim);
+        let y: f64 = oy * f64::from(self.dim);
+        let sx: i64 = x.floor() as i64;
+        let sy: i64 = y.floor() as i64;
 
-        let c1 = PerlinNoise::_to_vec(self.grid[sy
-                                        * (self.dim as usize) + sx]);
-        let c2 = PerlinNoise::_to_vec(self.grid[(sy + 1)
-                                        * (self.dim as usize) + sx]);
-        let c3 = PerlinNoise::_to_vec(self.grid[(sy + 1)
-                                        * (self.dim as usize) + (sx + 1)]);
-        let c4 = PerlinNoise::_to_vec(self.grid[sy
-                                        * (self.dim as usize) + (sx + 1)]);
+        let [c1, c2, c3, c4] = self._corners(sx, sy);
 
         let dc1 = (x - sx as f64, y - sy as f64);
         let dc2 = (x - sx as f64, y - (sy+1) as f64);
@@ -135,25 +223,387 @@ impl PerlinNoise {
             PerlinNoise::_fade(1.0 - (y - sy as f64))
         );
 
-        assert!(val <= 1.0);
-        assert!(val >= -1.0);
+        debug_assert!(val <= 1.0);
+        debug_assert!(val >= -1.0);
         return (1.0 + val) / 2.0;
     }
+
+    // returns the partial derivatives of `sample` with respect to
+    // ox and oy, computed analytically from the interpolant
+    fn sample_gradient(&self, ox: f64, oy: f64) -> (f64, f64) {
+        let x: f64 = ox * f64::from(self.dim);
+        let y: f64 = oy * f64::from(self.dim);
+        let sx: i64 = x.floor() as i64;
+        let sy: i64 = y.floor() as i64;
+
+        let [c1, c2, c3, c4] = self._corners(sx, sy);
+
+        let u = x - sx as f64;
+        let v = y - sy as f64;
+
+        let n1 = PerlinNoise::_dot((u, v), c1);
+        let n2 = PerlinNoise::_dot((u, v - 1.0), c2);
+        let n3 = PerlinNoise::_dot((u - 1.0, v - 1.0), c3);
+        let n4 = PerlinNoise::_dot((u - 1.0, v), c4);
+
+        let fu = PerlinNoise::_fade(u);
+        let fv = PerlinNoise::_fade(v);
+        let dfu = PerlinNoise::_fade_deriv(u);
+        let dfv = PerlinNoise::_fade_deriv(v);
+
+        // val = n1 + fu*(n4 - n1) + fv*(n2 - n1) + fu*fv*k
+        let k = n1 - n4 - n2 + n3;
+
+        let du = c1.0
+            + dfu * (n4 - n1) + fu * (c4.0 - c1.0)
+            + fv * (c2.0 - c1.0)
+            + dfu * fv * k + fu * fv * (c1.0 - c4.0 - c2.0 + c3.0);
+
+        let dv = c1.1
+            + fu * (c4.1 - c1.1)
+            + dfv * (n2 - n1) + fv * (c2.1 - c1.1)
+            + fu * dfv * k + fu * fv * (c1.1 - c4.1 - c2.1 + c3.1);
+
+        // chain rule through the scaling by dim and the final
+        // mapping of [-1, 1] to [0, 1]
+        let scale = f64::from(self.dim) / 2.0;
+        return (du * scale, dv * scale);
+    }
+}
+
+// A width x height image of the noise in `window`
+pub fn perlin<R: Rng>(freq: u16, width: usize, height: usize,
+                      tileable: bool, window: Window,
+                      rng: &mut R) -> Result<Field2D<f64>> {
+    let pn = PerlinNoise::new(freq, tileable, rng)?;
+    let short = cmp::min(width, height);
+
+    return Ok(Field2D::par_from_fn(width, height, |x, y| {
+        let (ox, oy) = window.at(x, y, short);
+        pn.sample(ox, oy)
+    }));
+}
+
+// The gradient with respect to position in the window, which is the
+// gradient of the noise scaled by the window's size
+fn _gradients(pn: &PerlinNoise, width: usize, height: usize,
+              window: Window) -> Field2D<(f64, f64)> {
+    let short = cmp::min(width, height);
+    return Field2D::par_from_fn(width, height, |x, y| {
+        let (ox, oy) = window.at(x, y, short);
+        let (dx, dy) = pn.sample_gradient(ox, oy);
+        (dx * window.size, dy * window.size)
+    });
+}
+
+// The curl of noise made of (frequency, weight) layers, like the octaves
+// of `make_octaves`. A single layer of weight 1 is plain Perlin noise.
+pub fn curl<R: Rng>(layers: &[(u16, f64)], width: usize, height: usize,
+                    tileable: bool, window: Window,
+                    rng: &mut R) -> Result<Field2D<(f64, f64)>> {
+    // treat the noise as a stream function: the velocity (dP/dy, -dP/dx)
+    // has zero divergence, so there are no sinks or sources. Curl is
+    // linear, so the curl of the weighted sum is the weighted sum of the
+    // layers' curls, which is divergence-free too.
+    let mut grad = Field2D::filled(width, height, (0.0, 0.0));
+    let mut unit = Field2D::filled(width, height, (0.0, 0.0));
+    for &(freq, weight) in layers {
+        let pn = PerlinNoise::new(freq, tileable, rng)?;
+        let add = |acc: &mut (f64, f64), &(dx, dy): &(f64, f64)| {
+            acc.0 += weight * dx;
+            acc.1 += weight * dy;
+        };
+
+        grad.zip_mut(&_gradients(&pn, width, height, window), add);
+        if !window.is_unit() {
+            unit.zip_mut(&_gradients(&pn, width, height, Window::unit()),
+                         add);
+        }
+    }
+
+    // scaled by the strongest flow in the unit square rather than in
+    // the window, so neighboring windows agree on their speeds
+    if window.is_unit() {
+        unit = grad.clone();
+    }
+
+    let max_mag = unit.map(|&(dx, dy)| (dx*dx + dy*dy).sqrt())
+        .max() * window.size;
+
+    let scale = if max_mag > 0.0 { 1.0 / max_mag } else { 0.0 };
+
+    return Ok(grad.map(|&(dx, dy)| (dy * scale, -dx * scale)));
 }
 
-pub fn perlin(freq: u16, size: usize) -> Vec<f64> {
-    let pn = PerlinNoise::new(freq);
-    let img_size = size;
+#[cfg(test)]
+mod tests {
+    use super::{perlin, curl, PerlinNoise, Window};
+    use rand::{Rng, SeedableRng, StdRng};
+
+    // Each property is checked over many random lattices and points
+    const TRIALS: usize = 200;
 
-    let mut out = Vec::new();
+    fn _noise(seed: usize, tileable: bool) -> (PerlinNoise, StdRng) {
+        let mut rng = StdRng::from_seed(&[seed]);
+        let dim = rng.gen_range(1, 17);
+        let pn = PerlinNoise::new(dim, tileable, &mut rng).unwrap();
+        return (pn, rng);
+    }
+
+    #[test]
+    fn lattice_points_are_one_half() {
+        for seed in 0..TRIALS {
+            let (pn, _) = _noise(seed, seed % 2 == 0);
+            let dim = f64::from(pn.dim);
 
-    for x in 0..size {
-        for y in 0..size {
-            let val = pn.sample(x as f64 / img_size as f64,
-                                y as f64 / img_size as f64);
-            out.push(val);
+            for i in 0..(pn.dim + 1) {
+                for j in 0..(pn.dim + 1) {
+                    let v = pn.sample(f64::from(i) / dim, f64::from(j) / dim);
+                    assert_eq!(v, 0.5, "dim {} point ({}, {})", pn.dim, i, j);
+                }
+            }
         }
     }
 
-    return out;
+    #[test]
+    fn continuous_across_cell_boundaries() {
+        let eps = 1e-9;
+
+        for seed in 0..TRIALS {
+            let (pn, mut rng) = _noise(seed, seed % 2 == 0);
+            let dim = f64::from(pn.dim);
+
+            for _ in 0..50 {
+                // a point on a vertical cell boundary, and one on a
+                // horizontal one
+                let edge = f64::from(rng.gen_range(1, pn.dim + 1)) / dim;
+                let along = rng.gen::<f64>();
+
+                let left = pn.sample(edge - eps, along);
+                let right = pn.sample((edge + eps).min(1.0), along);
+                assert!((left - right).abs() < 1e-6,
+                        "jump of {} at x = {}", left - right, edge);
+
+                let above = pn.sample(along, edge - eps);
+                let below = pn.sample(along, (edge + eps).min(1.0));
+                assert!((above - below).abs() < 1e-6,
+                        "jump of {} at y = {}", above - below, edge);
+            }
+        }
+    }
+
+    #[test]
+    fn unbounded_lattice_points_are_one_half() {
+        for seed in 0..TRIALS {
+            let (pn, mut rng) = _noise(seed, seed % 2 == 0);
+            let dim = i32::from(pn.dim);
+
+            for _ in 0..50 {
+                let i = rng.gen_range(-100 * dim, 100 * dim);
+                let j = rng.gen_range(-100 * dim, 100 * dim);
+                let v = pn.sample(f64::from(i) / f64::from(dim),
+                                  f64::from(j) / f64::from(dim));
+                assert!((v - 0.5).abs() < 1e-9,
+                        "dim {} point ({}, {}) is {}", pn.dim, i, j, v);
+            }
+        }
+    }
+
+    #[test]
+    fn continuous_outside_the_unit_square() {
+        let eps = 1e-9;
+
+        for seed in 0..TRIALS {
+            let (pn, mut rng) = _noise(seed, seed % 2 == 0);
+            let dim = i32::from(pn.dim);
+
+            for _ in 0..50 {
+                let edge = f64::from(rng.gen_range(-3 * dim, 4 * dim))
+                    / f64::from(dim);
+                let along = rng.gen_range(-3.0, 4.0);
+
+                let left = pn.sample(edge - eps, along);
+                let right = pn.sample(edge + eps, along);
+                assert!((left - right).abs() < 1e-6,
+                        "jump of {} at x = {}", left - right, edge);
+
+                let above = pn.sample(along, edge - eps);
+                let below = pn.sample(along, edge + eps);
+                assert!((above - below).abs() < 1e-6,
+                        "jump of {} at y = {}", above - below, edge);
+            }
+        }
+    }
+
+    #[test]
+    fn neighboring_windows_line_up() {
+        let n = 32;
+
+        for seed in 0..20 {
+            let tileable = seed % 2 == 0;
+            let whole = Window { x: 0.0, y: 0.0, size: 2.0 };
+            let right = Window { x: 1.0, y: 0.0, size: 1.0 };
+
+            let a = perlin(5, 2 * n, 2 * n, tileable, whole,
+                           &mut StdRng::from_seed(&[seed])).unwrap();
+            let b = perlin(5, n, n, tileable, right,
+                           &mut StdRng::from_seed(&[seed])).unwrap();
+
+            // the right half of the top half of `a` is all of `b`
+            for x in 0..n {
+                for y in 0..n {
+                    let va = a[(n + x, y)];
+                    let vb = b[(x, y)];
+                    assert!((va - vb).abs() < 1e-12,
+                            "({}, {}): {} != {}", x, y, va, vb);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wide_images_extend_the_window_sideways() {
+        let n = 24;
+
+        for seed in 0..20 {
+            let unit = Window::unit();
+            let right = Window { x: 1.0, y: 0.0, size: 1.0 };
+
+            let wide = perlin(3, 2 * n, n, false, unit,
+                              &mut StdRng::from_seed(&[seed])).unwrap();
+            let left = perlin(3, n, n, false, unit,
+                              &mut StdRng::from_seed(&[seed])).unwrap();
+            let next = perlin(3, n, n, false, right,
+                              &mut StdRng::from_seed(&[seed])).unwrap();
+
+            // the shorter side spans the window, so a 2:1 image shows
+            // the unit square and the square to its right
+            for x in 0..n {
+                for y in 0..n {
+                    assert!((wide[(x, y)] - left[(x, y)]).abs() < 1e-12);
+                    assert!((wide[(n + x, y)] - next[(x, y)]).abs() < 1e-12);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_lattice_point_uses_its_own_gradient() {
+        // next to a lattice point the noise is 0.5 plus half the dot
+        // product of the offset (in cells) with that point's gradient
+        let eps = 1e-6;
+
+        for seed in 0..TRIALS {
+            let (pn, _) = _noise(seed, false);
+            let dim = f64::from(pn.dim);
+
+            for i in 0..(pn.dim + 1) {
+                for j in 0..(pn.dim + 1) {
+                    // step into the grid, so the far edges work too
+                    let sx = if i == pn.dim { -1.0 } else { 1.0 };
+                    let sy = if j == pn.dim { -1.0 } else { 1.0 };
+                    let x = f64::from(i) / dim;
+                    let y = f64::from(j) / dim;
+
+                    let gx = (pn.sample(x + sx * eps / dim, y) - 0.5)
+                        * 2.0 / (sx * eps);
+                    let gy = (pn.sample(x, y + sy * eps / dim) - 0.5)
+                        * 2.0 / (sy * eps);
+
+                    // the lattice is stored row by row, dim + 1 wide
+                    let idx = j as usize * (pn.dim as usize + 1) + i as usize;
+                    let (ex, ey) = PerlinNoise::_to_vec(pn.grid[idx]);
+                    assert!((gx - ex).abs() < 1e-3 && (gy - ey).abs() < 1e-3,
+                            "dim {} point ({}, {}): got ({}, {}), \
+                             expected ({}, {})",
+                            pn.dim, i, j, gx, gy, ex, ey);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tileable_noise_wraps() {
+        for seed in 0..TRIALS {
+            let (pn, mut rng) = _noise(seed, true);
+
+            for _ in 0..50 {
+                let along = rng.gen::<f64>();
+                assert!((pn.sample(0.0, along) - pn.sample(1.0, along)).abs()
+                        < 1e-12);
+                assert!((pn.sample(along, 0.0) - pn.sample(along, 1.0)).abs()
+                        < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn gradient_matches_central_differences() {
+        let h = 1e-6;
+        for seed in 0..TRIALS {
+            let (pn, mut rng) = _noise(seed, false);
+
+            for _ in 0..20 {
+                let x = rng.gen_range(-1.0, 2.0);
+                let y = rng.gen_range(-1.0, 2.0);
+                let (gx, gy) = pn.sample_gradient(x, y);
+                let dx = (pn.sample(x + h, y) - pn.sample(x - h, y)) / (2.0 * h);
+                let dy = (pn.sample(x, y + h) - pn.sample(x, y - h)) / (2.0 * h);
+                assert!((gx - dx).abs() < 1e-5 * (1.0 + gx.abs()),
+                        "d/dx at ({}, {}): {} vs {}", x, y, gx, dx);
+                assert!((gy - dy).abs() < 1e-5 * (1.0 + gy.abs()),
+                        "d/dy at ({}, {}): {} vs {}", x, y, gy, dy);
+            }
+        }
+    }
+
+    #[test]
+    fn curl_is_divergence_free() {
+        let (w, h) = (256, 192);
+        for seed in 0..20 {
+            let mut rng = StdRng::from_seed(&[seed]);
+            let layers = [(rng.gen_range(1, 5), 1.0),
+                          (rng.gen_range(4, 9), 0.5)];
+            let v = curl(&layers, w, h, false, Window::unit(), &mut rng)
+                .unwrap();
+
+            // central differences of the field; the two terms of the
+            // divergence are large but should cancel
+            let mut div = 0.0;
+            let mut terms = 0.0;
+            for y in 1..h - 1 {
+                for x in 1..w - 1 {
+                    let dvx = (v[(x + 1, y)].0 - v[(x - 1, y)].0) / 2.0;
+                    let dvy = (v[(x, y + 1)].1 - v[(x, y - 1)].1) / 2.0;
+                    div += (dvx + dvy).abs();
+                    terms += dvx.abs() + dvy.abs();
+                }
+            }
+
+            assert!(div < 1e-2 * terms, "seed {}: {} vs {}", seed, div, terms);
+        }
+    }
+
+    #[test]
+    fn pixels_sample_the_noise_at_their_own_position() {
+        // a non-square image, so swapping x and y can't go unnoticed
+        let (width, height) = (12, 5);
+        let window = Window { x: 0.5, y: 1.0, size: 2.0 };
+
+        for seed in 0..20 {
+            let img = perlin(3, width, height, false, window,
+                             &mut StdRng::from_seed(&[seed])).unwrap();
+            let pn = PerlinNoise::new(3, false,
+                                      &mut StdRng::from_seed(&[seed]))
+                .unwrap();
+
+            assert_eq!((img.width(), img.height()), (width, height));
+            for y in 0..height {
+                for x in 0..width {
+                    let (ox, oy) = window.at(x, y, height);
+                    assert_eq!(img[(x, y)], pn.sample(ox, oy));
+                }
+            }
+        }
+    }
 }
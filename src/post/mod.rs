@@ -0,0 +1,242 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use grid::{Field2D, Weighted};
+use scene::Post;
+
+// The post-processing of one image. Every effect is linear in the
+// grids it's applied to, so stroke colors and flow statistics go
+// through the same passes as the flux and keep matching it.
+pub struct Plan<'a> {
+    post: &'a Post,
+
+    // length of the image's shorter side, which the radii are relative to
+    short: f64,
+
+    // how much of each pixel glows, from the blurred flux
+    bloom_mask: Option<Field2D<f64>>
+}
+
+pub fn is_enabled(post: &Post) -> bool {
+    return post.blur > 0.0 || post.bloom > 0.0
+        || post.sharpen > 0.0 || post.vignette > 0.0;
+}
+
+pub fn plan<'a>(post: &'a Post, flux: &Field2D<f64>,
+                max_flux: f64) -> Plan<'a> {
+    let short = flux.width().min(flux.height()) as f64;
+
+    let bloom_mask = if post.bloom > 0.0 {
+        let blurred = _blur_by(flux, post.blur * short);
+
+        // the part of each pixel above the threshold, as a fraction of
+        // the pixel, so it can scale colors and statistics as well
+        let threshold = post.bloom_threshold * max_flux;
+        Some(blurred.map(|&v| if v > threshold { (v - threshold) / v }
+                         else { 0.0 }))
+    } else {
+        None
+    };
+
+    return Plan {
+        post,
+        short,
+        bloom_mask
+    };
+}
+
+// Runs the blur, bloom, unsharp mask and vignette, in that order
+pub fn apply<T: Weighted>(plan: &Plan, grid: &Field2D<T>) -> Field2D<T> {
+    let post = plan.post;
+    let mut to_r = _blur_by(grid, post.blur * plan.short);
+
+    if let Some(ref mask) = plan.bloom_mask {
+        let bright = to_r.zip(mask, |v, &m| _scaled(v, m));
+        let glow = blur(&bright, post.bloom_radius * plan.short);
+        to_r.zip_mut(&glow, |v, g| v.add_weighted(g, post.bloom));
+    }
+
+    if post.sharpen > 0.0 {
+        let soft = blur(&to_r, post.sharpen_radius * plan.short);
+        to_r.zip_mut(&soft, |v, s| {
+            let sharp = *v;
+            v.add_weighted(&sharp, post.sharpen);
+            v.add_weighted(s, -post.sharpen);
+        });
+    }
+
+    if post.vignette > 0.0 {
+        to_r = vignette(&to_r, post.vignette);
+    }
+
+    return to_r;
+}
+
+fn _scaled<T: Weighted>(v: &T, s: f64) -> T {
+    let mut to_r = T::zero();
+    to_r.add_weighted(v, s);
+    return to_r;
+}
+
+fn _blur_by<T: Weighted>(grid: &Field2D<T>, sigma: f64) -> Field2D<T> {
+    if sigma <= 0.0 {
+        return grid.clone();
+    }
+
+    return blur(grid, sigma);
+}
+
+// The weights of a Gaussian out to three standard deviations, from
+// -radius to radius
+fn _kernel(sigma: f64) -> Vec<f64> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    return (-radius..(radius + 1))
+        .map(|d| (-((d * d) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+}
+
+// One pass of a separable blur along x (or y). The weights that fall
+// off the image are left out and the rest renormalized, so the edges
+// don't darken.
+fn _blur_pass<T: Weighted>(grid: &Field2D<T>, kernel: &[f64],
+                           along_x: bool) -> Field2D<T> {
+    let radius = (kernel.len() / 2) as i64;
+    let len = (if along_x { grid.width() } else { grid.height() }) as i64;
+
+    return Field2D::par_from_fn(grid.width(), grid.height(), |x, y| {
+        let at = (if along_x { x } else { y }) as i64;
+        let mut to_r = T::zero();
+        let mut total = 0.0;
+
+        for (k, &w) in kernel.iter().enumerate() {
+            let i = at + k as i64 - radius;
+            if i < 0 || i >= len {
+                continue;
+            }
+
+            let idx = if along_x { (i as usize, y) } else { (x, i as usize) };
+            to_r.add_weighted(&grid[idx], w);
+            total += w;
+        }
+
+        _scaled(&to_r, 1.0 / total)
+    });
+}
+
+// Gaussian blur with a standard deviation of sigma pixels
+pub fn blur<T: Weighted>(grid: &Field2D<T>, sigma: f64) -> Field2D<T> {
+    if sigma.is_nan() || sigma <= 0.0 {
+        return grid.clone();
+    }
+
+    let kernel = _kernel(sigma);
+    return _blur_pass(&_blur_pass(grid, &kernel, true), &kernel, false);
+}
+
+// Darkens towards the corners, by `amount` right in them
+pub fn vignette<T: Weighted>(grid: &Field2D<T>, amount: f64) -> Field2D<T> {
+    let cx = grid.width() as f64 / 2.0;
+    let cy = grid.height() as f64 / 2.0;
+    let corner = cx * cx + cy * cy;
+
+    return Field2D::par_from_fn(grid.width(), grid.height(), |x, y| {
+        let dx = x as f64 + 0.5 - cx;
+        let dy = y as f64 + 0.5 - cy;
+        let falloff = 1.0 - amount * (dx * dx + dy * dy) / corner;
+        _scaled(&grid[(x, y)], falloff.max(0.0))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, blur, plan, vignette};
+    use grid::Field2D;
+    use scene::Post;
+
+    #[test]
+    fn blur_keeps_a_constant_and_the_total() {
+        let flat = Field2D::filled(17, 9, 0.25);
+        assert!(blur(&flat, 2.5).iter().all(|v| (v - 0.25).abs() < 1e-12));
+
+        // a dot well away from the edges keeps its total brightness
+        let mut dot = Field2D::filled(41, 41, 0.0);
+        dot[(20, 20)] = 1.0;
+        let spread = blur(&dot, 3.0);
+        let total: f64 = spread.iter().sum();
+        assert!((total - 1.0).abs() < 1e-9);
+        assert!(spread[(20, 20)] < 1.0 && spread[(23, 20)] > 0.0);
+    }
+
+    #[test]
+    fn vignette_darkens_the_corners_only() {
+        let flat = Field2D::filled(20, 10, 1.0);
+        let v = vignette(&flat, 0.6);
+
+        assert!(v[(10, 5)] > 0.99);
+        assert!(v[(0, 0)] < 0.5);
+        assert!(v[(0, 0)] < v[(5, 2)]);
+    }
+
+    #[test]
+    fn bloom_only_glows_above_the_threshold() {
+        let post = Post { bloom: 1.0, bloom_threshold: 0.5, ..Post::default() };
+
+        // bright on the left, dim on the right and exactly at the
+        // threshold in the middle column
+        let flux = Field2D::from_fn(9, 4, |x, _| {
+            if x < 4 { 1.0 } else if x == 4 { 0.5 } else { 0.2 }
+        });
+        let p = plan(&post, &flux, 1.0);
+        let mask = p.bloom_mask.as_ref().unwrap();
+        for y in 0..4 {
+            assert!((mask[(0, y)] - 0.5).abs() < 1e-12);
+            assert_eq!(mask[(4, y)], 0.0);
+            assert_eq!(mask[(8, y)], 0.0);
+        }
+
+        // the threshold applies to the blurred flux, so a lone bright
+        // pixel that the blur spreads out doesn't glow at all
+        let mut dot = Field2D::filled(41, 41, 0.0);
+        dot[(20, 20)] = 1.0;
+        let sharp = plan(&post, &dot, 1.0);
+        assert!(sharp.bloom_mask.as_ref().unwrap()[(20, 20)] > 0.0);
+
+        let blurred = Post { blur: 0.05, ..post.clone() };
+        let soft = plan(&blurred, &dot, 1.0);
+        assert!(soft.bloom_mask.as_ref().unwrap().iter().all(|&m| m == 0.0));
+    }
+
+    #[test]
+    fn sharpen_keeps_a_constant_and_boosts_edges() {
+        let post = Post { sharpen: 1.0, sharpen_radius: 0.1, ..Post::default() };
+
+        let flat = Field2D::filled(20, 10, 0.3);
+        let p = plan(&post, &flat, 0.3);
+        assert!(apply(&p, &flat).iter().all(|v| (v - 0.3).abs() < 1e-12));
+
+        // a step from 0 to 1 overshoots on both sides of the edge
+        let step = Field2D::from_fn(20, 10, |x, _| if x < 10 { 0.0 } else { 1.0 });
+        let p = plan(&post, &step, 1.0);
+        let sharp = apply(&p, &step);
+        assert!(sharp[(9, 5)] < 0.0);
+        assert!(sharp[(10, 5)] > 1.0);
+        assert!((sharp[(0, 5)] - 0.0).abs() < 1e-3);
+        assert!((sharp[(19, 5)] - 1.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,1448 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std;
+use std::fs::File;
+use std::io;
+use std::cmp;
+use std::path::Path;
+use std::process::Command;
+use std::f64;
+use rand;
+use rand::{SeedableRng, StdRng};
+use rand::distributions::{IndependentSample, Range};
+use rayon::prelude::*;
+use image;
+use mktemp::Temp;
+
+use perlin::{perlin, curl, Window};
+use tracer::{Tracer, Field, Boundary, StrokeColor, ImageSample, FlowStats};
+use seeding;
+use seeding::{Seeding, DensitySampler};
+use field;
+use field::FieldFile;
+use input;
+use color;
+use color::{Rgb, Blend, Tone};
+use scene::{Scene, Noise, Layer};
+use metadata;
+use metadata::Metadata;
+use grid::{Field2D, Lerp, Weighted};
+use filter;
+use filter::Filter;
+use post;
+use post::Plan;
+use error;
+use error::{Error, Result};
+
+fn _lerp(a: f64, b: f64, w: f64) -> f64 {
+    debug_assert!(w >= 0.0);
+    debug_assert!(w <= 1.0);
+    return w*a + (1.0 - w)*b;
+}
+
+// Saves an image in the format given by the file extension. PNGs also
+// carry the metadata, which the other formats drop.
+#[allow(deprecated)] // image 0.18 can only write PNM images through PPM
+pub fn save_image(img: image::DynamicImage, name: &Path,
+                  meta: &[(String, String)]) -> Result<()> {
+    let what = format!("Could not write {}", name.display());
+    let format = match name.extension().and_then(|e| e.to_str()) {
+        Some("jpg") | Some("jpeg") => image::JPEG,
+        Some("bmp") => image::BMP,
+        Some("ppm") => image::PPM,
+        _ => {
+            let (w, h, alpha, pixels) = match img {
+                image::ImageRgba8(rgba) => {
+                    let (w, h) = rgba.dimensions();
+                    (w, h, true, rgba.into_raw())
+                },
+                other => {
+                    let rgb = other.to_rgb();
+                    let (w, h) = rgb.dimensions();
+                    (w, h, false, rgb.into_raw())
+                }
+            };
+            return metadata::write_png(name, w as usize, h as usize, alpha,
+                                       &pixels, meta)
+                .map_err(error::io(what));
+        }
+    };
+
+    let fout = &mut File::create(name).map_err(error::io(what.clone()))?;
+    return img.save(fout, format).map_err(error::image(what));
+}
+
+// Colors normalized flux through one of the fixed color functions
+pub fn color_image(data: &Field2D<f64>,
+                   color_func: u8,
+                   tone: &Tone) -> Result<image::RgbImage> {
+
+    if color_func > 3 {
+        return error::invalid(format!(
+            "Unknown color function {} (expected 0 through 3)", color_func));
+    }
+
+    let mut imgbuf = image::ImageBuffer::new(data.width() as u32,
+                                             data.height() as u32);
+    
+    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+        let val = tone.apply(data[(x as usize, y as usize)]);
+
+        match color_func {
+            0 => {
+                *pixel = image::Rgb([(val*180.0) as u8,
+                                     20,
+                                     (val*225.0) as u8]);
+            },
+
+            1 => {
+                *pixel = image::Rgb([(val*20.0) as u8,
+                                     (val*180.0) as u8,
+                                     (val*225.0) as u8]);
+            },
+
+            2 => {                
+                *pixel = image::Rgb([(val*200.0) as u8,
+                                     (val*180.0) as u8,
+                                     (val*90.0) as u8]);
+            },
+
+            3 => {
+                let c1_r = 125.0;
+                let c1_g = 186.0;
+                let c1_b = 182.0;
+                
+                let c2_r = 46.0;
+                let c2_g = 59.0;
+                let c2_b = 65.0;
+                
+                *pixel = image::Rgb([_lerp(c1_r, c2_r, val) as u8,
+                                     _lerp(c1_g, c2_g, val) as u8,
+                                     _lerp(c1_b, c2_b, val) as u8]);
+            },
+
+            _ => unreachable!()
+        }
+        
+    }    
+
+    return Ok(imgbuf);
+}
+
+// What the color functions show where there is no flux
+fn function_background(color_func: u8) -> Rgb {
+    return match color_func {
+        0 => (0.0, 20.0 / 255.0, 0.0),
+        3 => (46.0 / 255.0, 59.0 / 255.0, 65.0 / 255.0),
+        _ => (0.0, 0.0, 0.0)
+    };
+}
+
+// The top and bottom colors of the background (black when none is set)
+fn background_of(scene: &Scene) -> Result<(Rgb, Rgb)> {
+    let colors = scene.color.background.iter()
+        .map(|c| color::parse_hex(c).ok_or_else(|| Error::Invalid(
+            format!("{} is not a color (expected #rrggbb)", c))))
+        .collect::<Result<Vec<Rgb>>>()?;
+
+    return match colors.len() {
+        0 => Ok(((0.0, 0.0, 0.0), (0.0, 0.0, 0.0))),
+        1 => Ok((colors[0], colors[0])),
+        2 => Ok((colors[0], colors[1])),
+        _ => error::invalid("A background has one or two colors")
+    };
+}
+
+// Puts the strokes of a colored image over the scene's background, or
+// over nothing for transparent output. The flux says how much of each
+// pixel the strokes cover; `own` is the background the coloring drew
+// them over, which is taken back out first (None when the coloring
+// fills every pixel itself).
+fn with_background(scene: &Scene,
+                   img: image::RgbImage,
+                   flux: &Field2D<f64>,
+                   max_flux: f64,
+                   own: Option<Rgb>) -> Result<image::DynamicImage> {
+    if !scene.output.transparent && scene.color.background.is_empty() {
+        return Ok(image::ImageRgb8(img));
+    }
+
+    let tone = &scene.tone;
+    let strokes = Field2D::from_fn(flux.width(), flux.height(), |x, y| {
+        let cover = if max_flux > 0.0 {
+            tone.apply(flux[(x, y)] / max_flux)
+        } else {
+            0.0
+        };
+
+        let px = img.get_pixel(x as u32, y as u32).data;
+        let c = (f64::from(px[0]) / 255.0,
+                 f64::from(px[1]) / 255.0,
+                 f64::from(px[2]) / 255.0);
+
+        // c is the stroke color over `own`, `cover` of the way
+        let unmix = |c: f64, d: f64| {
+            ((c - (1.0 - cover) * d) / cover).clamp(0.0, 1.0)
+        };
+        let stroke = match own {
+            Some(_) if cover <= 0.0 => (0.0, 0.0, 0.0),
+            Some(d) => (unmix(c.0, d.0), unmix(c.1, d.1), unmix(c.2, d.2)),
+            None => c
+        };
+
+        (stroke, cover)
+    });
+
+    if scene.output.transparent {
+        let mut imgbuf = image::ImageBuffer::new(flux.width() as u32,
+                                                 flux.height() as u32);
+        for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+            let ((r, g, b), a) = strokes[(x as usize, y as usize)];
+            *pixel = image::Rgba([(r * 255.0) as u8,
+                                  (g * 255.0) as u8,
+                                  (b * 255.0) as u8,
+                                  (a * 255.0) as u8]);
+        }
+
+        return Ok(image::ImageRgba8(imgbuf));
+    }
+
+    let (top, bottom) = background_of(scene)?;
+    let height = flux.height() as f64;
+    let res = Field2D::from_fn(flux.width(), flux.height(), |x, y| {
+        let back = Rgb::lerp(top, bottom, (y as f64 + 0.5) / height);
+        let (stroke, cover) = strokes[(x, y)];
+        Rgb::lerp(back, stroke, cover)
+    });
+
+    return Ok(image::ImageRgb8(rgb_image(&res)));
+}
+
+pub fn gray_image(data: &Field2D<f64>) -> image::RgbImage {
+
+    let mut imgbuf = image::ImageBuffer::new(data.width() as u32,
+                                             data.height() as u32);
+    
+    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+        let val = data[(x as usize, y as usize)];
+
+        *pixel = image::Rgb([(val * 255.0) as u8,
+                             (val * 255.0) as u8,
+                             (val * 255.0) as u8]);
+    }
+        
+    return imgbuf;
+}
+
+pub fn rgb_image(data: &Field2D<Rgb>) -> image::RgbImage {
+
+    let mut imgbuf = image::ImageBuffer::new(data.width() as u32,
+                                             data.height() as u32);
+
+    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+        let (r, g, b) = data[(x as usize, y as usize)];
+
+        *pixel = image::Rgb([(r * 255.0) as u8,
+                             (g * 255.0) as u8,
+                             (b * 255.0) as u8]);
+    }
+
+    return imgbuf;
+}
+
+#[derive(Clone, Copy)]
+enum FieldColor {
+    // hue from the mean heading, brightness from the density
+    Heading,
+
+    // mean speed through the color function
+    Speed,
+
+    // mean path curvature through the color function
+    Curvature,
+
+    // hue from the mean heading, brightness from the mean speed
+    HeadingSpeed
+}
+
+fn _unknown<T>(what: &str, value: &str, expected: &str) -> Result<T> {
+    return error::invalid(format!("Unknown {} \"{}\" (expected {})",
+                                  what, value, expected));
+}
+
+fn boundary_of(scene: &Scene) -> Result<Boundary> {
+    let emitter = &scene.particles.emitter;
+    if emitter.len() != 2 && emitter.len() != 3 {
+        return error::invalid("Emitter must be given as x,y or x,y,radius");
+    }
+
+    let radius = *emitter.get(2).unwrap_or(&0.02);
+    if radius.is_nan() || radius <= 0.0 {
+        return error::invalid("Emitter radius must be positive");
+    }
+
+    return Ok(match scene.particles.boundary.as_str() {
+        "discard" => Boundary::Discard,
+        "wrap" => Boundary::Wrap,
+        "reflect" => Boundary::Reflect,
+        "respawn" => Boundary::Respawn,
+        "emitter" => Boundary::Emitter(emitter[0], emitter[1], radius),
+        "clamp" => Boundary::Clamp,
+        other => return _unknown("boundary", other,
+                                 "discard, wrap, reflect, respawn, \
+                                  emitter or clamp")
+    });
+}
+
+fn stroke_color_of(scene: &Scene) -> Result<Option<StrokeColor>> {
+    let sample = match scene.color.sample.as_str() {
+        "spawn" => ImageSample::Spawn,
+        "current" => ImageSample::Current,
+        other => return _unknown("color sample", other, "spawn or current")
+    };
+
+    return Ok(match scene.color.stroke.as_deref() {
+        None => None,
+        Some("position") => Some(StrokeColor::Position),
+        Some("speed") => Some(StrokeColor::Speed),
+        Some("heading") => Some(StrokeColor::Heading),
+        Some("age") => Some(StrokeColor::Age),
+        Some("image") => Some(StrokeColor::Image(sample)),
+        Some(other) => return _unknown("stroke color", other,
+                                       "position, speed, heading, age \
+                                        or image")
+    });
+}
+
+fn blend_of(scene: &Scene) -> Result<Blend> {
+    return match scene.color.blend.as_str() {
+        "additive" => Ok(Blend::Additive),
+        "average" => Ok(Blend::Average),
+        other => _unknown("blend", other, "additive or average")
+    };
+}
+
+fn field_color_of(scene: &Scene) -> Result<Option<FieldColor>> {
+    return Ok(match scene.color.field.as_deref() {
+        None => None,
+        Some("heading") => Some(FieldColor::Heading),
+        Some("speed") => Some(FieldColor::Speed),
+        Some("curvature") => Some(FieldColor::Curvature),
+        Some("heading-speed") => Some(FieldColor::HeadingSpeed),
+        Some(other) => return _unknown("field color", other,
+                                       "heading, speed, curvature or \
+                                        heading-speed")
+    });
+}
+
+// Whether counts and widths are relative to the canvas rather than
+// the output pixels
+fn canvas_units(scene: &Scene) -> Result<bool> {
+    return match scene.world.units.as_str() {
+        "pixels" => Ok(false),
+        "canvas" => Ok(true),
+        other => _unknown("units", other, "pixels or canvas")
+    };
+}
+
+// With canvas units, the density is per square of the canvas' shorter
+// side, so the count depends on the aspect ratio but not the resolution
+fn particle_count(scene: &Scene) -> Result<usize> {
+    let img_width = scene.output.width;
+    let img_height = scene.output.height;
+
+    return Ok(match scene.seeding.particles {
+        Some(count) => count,
+        None if canvas_units(scene)? => {
+            let short = cmp::min(img_width, img_height) as f64;
+            let area = img_width as f64 * img_height as f64 / (short * short);
+            (scene.world.density * area).round() as usize
+        },
+        None => img_width * img_height
+    });
+}
+
+fn seeding_of(scene: &Scene) -> Result<Seeding> {
+    return Ok(match scene.seeding.strategy.as_str() {
+        "grid" => Seeding::Grid,
+        "random" => Seeding::Random,
+        "jitter" => Seeding::Jittered,
+        "poisson" => Seeding::Poisson,
+        "halton" => Seeding::Halton,
+        "sobol" => Seeding::Sobol,
+        "density" => {
+            let path = match scene.seeding.image {
+                Some(ref path) => path,
+                None => return error::invalid("Density seeding needs a \
+                                               seed image")
+            };
+            Seeding::Density(input::load_grayscale(Path::new(path),
+                                                   scene.output.width,
+                                                   scene.output.height)?)
+        },
+        other => return _unknown("seeding strategy", other,
+                                 "grid, random, jitter, poisson, halton, \
+                                  sobol or density")
+    });
+}
+
+fn flow_stats_image(flux: &Field2D<f64>,
+                    stats: &Field2D<FlowStats>,
+                    max_flux: f64,
+                    color_func: u8,
+                    tone: &Tone,
+                    field_color: FieldColor) -> Result<image::RgbImage> {
+
+    let means = stats.zip(flux, |st, &k| {
+        if k <= 0.0 {
+            return FlowStats::default();
+        }
+
+        FlowStats {
+            speed: st.speed / k,
+            heading_cos: st.heading_cos / k,
+            heading_sin: st.heading_sin / k,
+            curvature: st.curvature / k
+        }
+    });
+
+    let max_speed = means.iter()
+        .fold(0.0, |a: f64, st| a.max(st.speed));
+    let max_curvature = means.iter()
+        .fold(0.0, |a: f64, st| a.max(st.curvature));
+
+    let heading_hue = |st: &FlowStats| {
+        st.heading_sin.atan2(st.heading_cos) / (2.0 * f64::consts::PI)
+    };
+
+    return Ok(match field_color {
+        FieldColor::Speed => {
+            let res = means
+                .map(|st| if max_speed > 0.0 { st.speed / max_speed }
+                     else { 0.0 });
+            color_image(&res, color_func, tone)?
+        },
+
+        FieldColor::Curvature => {
+            // curvature is heavy-tailed, so compress it first
+            let res = means
+                .map(|st| if max_curvature > 0.0 {
+                    st.curvature.ln_1p() / max_curvature.ln_1p()
+                } else { 0.0 });
+            color_image(&res, color_func, tone)?
+        },
+
+        FieldColor::Heading => {
+            let res = means.zip(flux, |st, &k| {
+                // the length of the mean heading vector says how
+                // much the particles through this pixel agree
+                let agreement = st.heading_cos.hypot(st.heading_sin);
+                color::hsv_to_rgb(heading_hue(st),
+                                  agreement.min(1.0),
+                                  tone.apply(k / max_flux))
+            });
+            rgb_image(&res)
+        },
+
+        FieldColor::HeadingSpeed => {
+            let res = means
+                .map(|st| {
+                    let bright = if max_speed > 0.0 {
+                        st.speed / max_speed
+                    } else { 0.0 };
+                    color::hsv_to_rgb(heading_hue(st), 1.0, bright)
+                });
+            rgb_image(&res)
+        }
+    });
+}
+
+fn make_tracers(scene: &Scene,
+                seed: u64,
+                ff: &FieldFile,
+                seeds: &[(f64, f64)],
+                mask: Option<&Field2D<f64>>,
+                color_image: Option<&Field2D<Rgb>>,
+                respawn: Option<&DensitySampler>) -> Result<Vec<Tracer>> {
+    
+    let boundary = boundary_of(scene)?;
+    let stroke_color = stroke_color_of(scene)?;
+    let particles = &scene.particles;
+
+    let mut tracers = Vec::new();
+    for job in 0..scene.jobs {
+        let (w, h) = trace_size(scene);
+        let mut t = Tracer::new(&ff.field, w, h, ff.phase)?;
+        t.set_rng(_rng(seed, job + 1));
+        t.set_boundary(boundary);
+        t.set_lifetime(particles.lifetime, particles.lifetime_spread)?;
+        t.set_fade(particles.fade)?;
+        t.set_friction(scene.integrator.friction)?;
+        t.set_interpolate(scene.field.interpolate);
+        if canvas_units(scene)? {
+            t.set_stroke_width(scene.world.stroke_width)?;
+        }
+        if let Some(m) = mask {
+            t.set_mask(m.clone())?;
+        }
+        if let Some(d) = respawn {
+            t.set_respawn_density(d.clone());
+        }
+        if let Some(sc) = stroke_color {
+            t.set_stroke_color(sc);
+        }
+        if let Some(img) = color_image {
+            t.set_color_image(img.clone())?;
+        }
+        if scene.color.field.is_some() {
+            t.record_stats();
+        }
+        tracers.push(t);
+    }
+    
+    let mut c = 0;
+    for &(part_x, part_y) in seeds {
+        tracers[c].add_particle(part_x, part_y)?;
+        c = (c + 1) % tracers.len();
+    }
+
+    return Ok(tracers);
+}
+
+// The layers `--octaves` has always used
+fn default_octaves() -> Vec<Layer> {
+    return vec![Layer { frequency: 2, weight: 0.10 },
+                Layer { frequency: 4, weight: 0.20 },
+                Layer { frequency: 8, weight: 0.40 }];
+}
+
+fn make_octaves(layers: &[Layer], width: usize, height: usize,
+                tileable: bool, window: Window,
+                rng: &mut StdRng) -> Result<Field2D<f64>> {
+    let mut to_r = Field2D::filled(width, height, 0.0);
+    for l in layers {
+        let d = perlin(l.frequency, width, height, tileable, window, rng)?;
+        to_r.zip_mut(&d, |acc, &v| *acc += ((v * 2.0) - 1.0) * l.weight);
+    }
+
+    // normalize. A window onto part of the noise uses the bounds of
+    // the sum instead of its actual range, which differs from tile to
+    // tile, so that neighboring windows line up.
+    if window.is_unit() {
+        return Ok(to_r.normalize());
+    }
+
+    let bound: f64 = layers.iter().map(|l| l.weight.abs()).sum();
+    return Ok(to_r.rescale(-bound, bound));
+}
+
+// A single layer is plain Perlin noise; more are combined as octaves
+fn make_noise(noise: &Noise, width: usize, height: usize,
+              rng: &mut StdRng) -> Result<Field2D<f64>> {
+    if noise.layers.is_empty() {
+        return perlin(noise.frequency, width, height, noise.tileable,
+                      noise.window(), rng);
+    }
+
+    return make_octaves(&noise.layers, width, height, noise.tileable,
+                        noise.window(), rng);
+}
+
+// The resolution the particles are traced at
+fn trace_size(scene: &Scene) -> (usize, usize) {
+    let ssaa = scene.output.ssaa;
+    return (scene.output.width * ssaa, scene.output.height * ssaa);
+}
+
+fn filter_of(scene: &Scene) -> Result<Filter> {
+    return Ok(match scene.output.filter.as_str() {
+        "box" => Filter::Box,
+        "lanczos" => Filter::Lanczos,
+        "mitchell" => Filter::Mitchell,
+        other => return _unknown("filter", other, "box, lanczos or mitchell")
+    });
+}
+
+// Brings a grid traced at `trace_size` down to the output size
+fn resolve<T: Weighted>(scene: &Scene,
+                        grid: Field2D<T>) -> Result<Field2D<T>> {
+    if scene.output.ssaa == 1 {
+        return Ok(grid);
+    }
+
+    let filter = filter_of(scene)?;
+    return Ok(filter::downsample(&grid, scene.output.ssaa, filter));
+}
+
+// The flux of all of the tracers together, at the output size. The
+// sharper filters can undershoot next to bright strokes, which
+// is clipped.
+fn total_flux(scene: &Scene, tracers: &[Tracer]) -> Result<Field2D<f64>> {
+    let (first, rest) = match tracers.split_first() {
+        Some(split) => split,
+        None => return error::invalid("There are no tracers to combine")
+    };
+
+    let mut to_r = first.get_unnormalized_flux().clone();
+    for t in rest {
+        to_r.zip_mut(t.get_unnormalized_flux(), |acc, v| *acc += v);
+    }
+
+    if scene.output.ssaa == 1 {
+        return Ok(to_r);
+    }
+
+    return Ok(resolve(scene, to_r)?.map(|&v| v.max(0.0)));
+}
+
+fn _post<T: Weighted>(plan: Option<&Plan>, grid: Field2D<T>) -> Field2D<T> {
+    return match plan {
+        Some(plan) => post::apply(plan, &grid),
+        None => grid
+    };
+}
+
+fn tracers_image(scene: &Scene,
+                 tracers: &[Tracer],
+                 max_flux: f64) -> Result<image::DynamicImage> {
+
+    let (trace_width, trace_height) = trace_size(scene);
+    let color_func = scene.color.function;
+    let tone = &scene.tone;
+    
+    let flux = total_flux(scene, tracers)?;
+    let plan = if post::is_enabled(&scene.post) {
+        Some(post::plan(&scene.post, &flux, max_flux))
+    } else {
+        None
+    };
+
+    // sharpening can undershoot too
+    let flux = match plan {
+        Some(ref plan) => post::apply(plan, &flux).map(|&v| v.max(0.0)),
+        None => flux
+    };
+    
+    if let Some(fc) = field_color_of(scene)? {
+        let mut stats = Field2D::filled(trace_width, trace_height,
+                                        FlowStats::default());
+
+        for t in tracers {
+            let grid = match t.get_stats() {
+                Some(grid) => grid,
+                None => return error::invalid("Field colors need every \
+                                               tracer to record stats")
+            };
+            stats.zip_mut(grid, |acc, v| acc.add(v));
+        }
+
+        let stats = _post(plan.as_ref(), resolve(scene, stats)?);
+        let img = flow_stats_image(&flux, &stats, max_flux,
+                                   color_func, tone, fc)?;
+
+        // only the heading is drawn over black; the others color
+        // every pixel
+        let own = match fc {
+            FieldColor::Heading => Some((0.0, 0.0, 0.0)),
+            _ => None
+        };
+        return with_background(scene, img, &flux, max_flux, own);
+    }
+
+    if tracers[0].get_color_flux().is_some() {
+        let mut colors = Field2D::filled(trace_width, trace_height,
+                                         (0.0, 0.0, 0.0));
+
+        for t in tracers {
+            let grid = match t.get_color_flux() {
+                Some(grid) => grid,
+                None => return error::invalid("Stroke colors need every \
+                                               tracer to have one")
+            };
+            colors.zip_mut(grid, |acc, v| {
+                acc.0 += v.0;
+                acc.1 += v.1;
+                acc.2 += v.2;
+            });
+        }
+
+        let colors = _post(plan.as_ref(), resolve(scene, colors)?);
+        let blend = blend_of(scene)?;
+        let res = colors.zip(&flux, |&c, &k| {
+            color::blend(c, k, max_flux, blend, tone)
+        });
+
+        return with_background(scene, rgb_image(&res), &flux, max_flux,
+                               Some((0.0, 0.0, 0.0)));
+    }
+
+    let img = color_image(&flux.rescale(0.0, max_flux), color_func, tone)?;
+    return with_background(scene, img, &flux, max_flux,
+                           Some(function_background(color_func)));
+}
+
+fn write_tracers(out_name: &Path,
+                 scene: &Scene,
+                 tracers: &[Tracer],
+                 max_flux: f64,
+                 meta: &[(String, String)]) -> Result<()> {
+    let img = tracers_image(scene, tracers, max_flux)?;
+    return save_image(img, out_name, meta);
+}
+
+// The file name (with extension) of the main output
+pub fn output_path(scene: &Scene) -> String {
+    return format!("{}.{}", scene.output.name, scene.output.format);
+}
+
+// Independent, repeatable random streams derived from one seed
+fn _rng(seed: u64, stream: usize) -> StdRng {
+    return StdRng::from_seed(&[seed as usize, (seed >> 32) as usize, stream]);
+}
+
+fn _colormap(scene: &Scene) -> String {
+    if let Some(ref fc) = scene.color.field {
+        return format!("field-{}", fc);
+    }
+
+    if let Some(ref sc) = scene.color.stroke {
+        return format!("stroke-{} ({} blend)", sc, scene.color.blend);
+    }
+
+    return format!("function-{}", scene.color.function);
+}
+
+// What gets written into each PNG: the full scene (with its seed), so
+// `perlin reproduce` can re-render it, plus the headline parameters for
+// anyone reading the file with other tools
+fn make_metadata(scene: &Scene, phase: Option<f64>) -> Result<Metadata> {
+    let seed = match scene.seed {
+        Some(seed) => seed,
+        None => return error::invalid("The scene has no seed to record")
+    };
+
+    let noise = if scene.mode == "flow" {
+        scene.field.kind.clone()
+    } else {
+        scene.mode.clone()
+    };
+
+    let mut to_r = vec![
+        (String::from("Software"),
+         format!("perlin {}", env!("CARGO_PKG_VERSION"))),
+        (String::from("version"), String::from(env!("CARGO_PKG_VERSION"))),
+        (String::from("seed"),
+         seed.to_string()),
+        (String::from("frequency"), scene.noise.frequency.to_string()),
+        (String::from("noise"), noise),
+        (String::from("colormap"), _colormap(scene)),
+        (String::from("dt"), scene.integrator.dt.to_string()),
+        (String::from("steps"), scene.integrator.steps.to_string())
+    ];
+
+    if let Some(phase) = phase {
+        to_r.push((String::from("phase"), phase.to_string()));
+    }
+
+    to_r.push((String::from("scene"), scene.to_toml()?));
+    return Ok(to_r);
+}
+
+// The most cells a flow field may have, which is already a few
+// gigabytes of vectors
+const MAX_FIELD_CELLS: usize = 1 << 28;
+
+// The flow field's own resolution. It always covers the whole canvas,
+// so the composition doesn't depend on the output size.
+fn field_size(scene: &Scene) -> Result<(usize, usize)> {
+    let img_width = scene.output.width;
+    let img_height = scene.output.height;
+    let short = cmp::min(img_width, img_height);
+    let cells = scene.field.resolution.unwrap_or((short / 2).max(1));
+
+    let (fw, fh) = match scene.field.aspect.as_str() {
+        "square" => (cells, cells),
+        "canvas" => {
+            let long = cmp::max(img_width, img_height) as f64
+                / short as f64 * cells as f64;
+            let long = long.round() as usize;
+
+            if img_width >= img_height {
+                (long, cells)
+            } else {
+                (cells, long)
+            }
+        },
+        other => return _unknown("field aspect", other, "square or canvas")
+    };
+
+    // a huge resolution would otherwise abort when the field is allocated
+    if fw.checked_mul(fh).is_none_or(|n| n > MAX_FIELD_CELLS) {
+        return error::invalid("The flow field is too large");
+    }
+
+    return Ok((fw, fh));
+}
+
+fn make_field(scene: &Scene, phase: f64,
+              rng: &mut StdRng) -> Result<FieldFile> {
+    if let Some(ref path) = scene.field.load {
+        // a file that can be read but isn't a field is bad input rather
+        // than an I/O failure
+        let ff = field::load(Path::new(path)).map_err(|e| {
+            let what = format!("Could not load the flow field {}", path);
+            match e.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof =>
+                    Error::Invalid(format!("{}: {}", what, e)),
+                _ => Error::Io(what, e)
+            }
+        })?;
+        eprintln!("Loaded flow field ({})", ff.generator);
+        return Ok(ff);
+    }
+
+    let (fw, fh) = field_size(scene)?;
+    let noise = &scene.noise;
+    let field_type = scene.field.kind.as_str();
+
+    let d1 = make_noise(noise, fw, fh, rng)?;
+
+    let field = match field_type {
+        "angle" => Field::Angle(d1),
+        "curl" => {
+            let layers: Vec<(u16, f64)> = if noise.layers.is_empty() {
+                vec![(noise.frequency, 1.0)]
+            } else {
+                noise.layers.iter().map(|l| (l.frequency, l.weight)).collect()
+            };
+            Field::Curl(curl(&layers, fw, fh, noise.tileable,
+                             noise.window(), rng)?)
+        },
+        "image-angle" | "image-gradient" | "image-edges" => {
+            let path = match scene.field.image {
+                Some(ref path) => path,
+                None => return error::invalid(format!(
+                    "The {} field needs a field image", field_type))
+            };
+            let img = input::load_grayscale(Path::new(path), fw, fh)?;
+
+            let vecs = match field_type {
+                "image-angle" =>
+                    field::angles_to_vectors(&img, phase),
+                "image-gradient" => field::normalize(
+                    &field::sobel(&img)),
+                _ => field::normalize(&field::perpendicular(
+                    &field::sobel(&img)))
+            };
+
+            let noise = field::angles_to_vectors(&d1, phase);
+            Field::Vector(field::mix(&vecs, &noise, scene.field.noise_mix))
+        },
+        other => return _unknown("field", other,
+                                 "angle, curl, image-angle, \
+                                  image-gradient or image-edges")
+    };
+
+    return Ok(FieldFile {
+        field,
+        phase,
+        generator: format!("perlin field={} frequency={} \
+                            tileable={} noise-mix={}",
+                           field_type, noise.frequency, noise.tileable,
+                           scene.field.noise_mix)
+    });
+}
+
+// Catches parameters that would otherwise panic (or quietly produce
+// garbage) deep inside a render
+pub fn validate(scene: &Scene) -> Result<()> {
+    if scene.output.width == 0 || scene.output.height == 0 {
+        return error::invalid("The output must be at least 1x1 pixels");
+    }
+
+    if scene.color.function > 3 {
+        return error::invalid(format!(
+            "Unknown color function {} (expected 0 through 3)",
+            scene.color.function));
+    }
+
+    if scene.noise.frequency == 0
+        || scene.noise.layers.iter().any(|l| l.frequency == 0) {
+        return error::invalid("Noise frequency must be at least 1");
+    }
+
+    // a color image is a kind of stroke color
+    if scene.color.field.is_some() && scene.color.stroke.is_some() {
+        return error::invalid("Field colors can't be combined with stroke \
+                               colors or a color image");
+    }
+
+    if scene.output.ssaa == 0 {
+        return error::invalid("The supersampling factor must be at least 1");
+    }
+
+    let ssaa = scene.output.ssaa;
+    if scene.output.width.checked_mul(ssaa)
+        .and_then(|w| scene.output.height.checked_mul(ssaa)
+                  .and_then(|h| w.checked_mul(h)))
+        .is_none() {
+        return error::invalid("The supersampled canvas is too large");
+    }
+
+    background_of(scene)?;
+
+    if scene.output.transparent && scene.output.format != "png" {
+        return error::invalid("Only PNG output can be transparent");
+    }
+
+    if scene.jobs == 0 {
+        return error::invalid("At least one job is needed");
+    }
+
+    // the images can come from the scene file as well as the command
+    // line, so these are only known once the two are merged
+    let traced = scene.mode == "flow";
+    if traced && scene.seeding.strategy == "density"
+        && scene.seeding.image.is_none() {
+        return error::invalid("Density seeding needs a seed image");
+    }
+
+    if (traced || scene.mode == "field") && scene.field.load.is_none()
+        && scene.field.kind.starts_with("image-")
+        && scene.field.image.is_none() {
+        return error::invalid(format!("The {} field needs a field image",
+                                      scene.field.kind));
+    }
+
+    // only angles fit in a PNG, so don't generate a field that can't
+    // be saved
+    if let Some(ref path) = scene.field.save {
+        if field::angles_only(Path::new(path)) && scene.field.load.is_none()
+            && scene.field.kind != "angle" {
+            return error::invalid(format!(
+                "Only angle fields can be saved as PNG, not {}",
+                scene.field.kind));
+        }
+    }
+
+    if scene.field.resolution == Some(0) {
+        return error::invalid("The field needs at least one cell");
+    }
+
+    if traced || scene.mode == "field" {
+        field_size(scene)?;
+    }
+
+    if !(scene.field.noise_mix >= 0.0 && scene.field.noise_mix <= 1.0) {
+        return error::invalid("Noise mix must be between 0 and 1");
+    }
+
+    if !(scene.particles.fade >= 0.0 && scene.particles.fade <= 0.5) {
+        return error::invalid("Fade must be between 0 and 0.5");
+    }
+
+    if !(0.0..=1.0).contains(&scene.particles.lifetime_spread) {
+        return error::invalid("Lifetime spread must be between 0 and 1");
+    }
+
+    if !(scene.integrator.friction >= 0.0
+         && scene.integrator.friction.is_finite()) {
+        return error::invalid("Friction must be a non-negative number");
+    }
+
+    if scene.seeding.particles == Some(0) {
+        return error::invalid("At least one particle is needed");
+    }
+
+    if scene.tone.exposure.is_nan() || scene.tone.exposure < 0.0 {
+        return error::invalid("Exposure must not be negative");
+    }
+
+    if scene.tone.gamma.is_nan() || scene.tone.gamma <= 0.0 {
+        return error::invalid("Gamma must be positive");
+    }
+
+    if !(scene.noise.scale > 0.0 && scene.noise.scale.is_finite()) {
+        return error::invalid("The noise scale must be positive");
+    }
+
+    if !scene.noise.offset.iter().all(|o| o.is_finite()) {
+        return error::invalid("The noise offset must be finite");
+    }
+
+    if !(scene.world.density >= 0.0 && scene.world.density.is_finite()) {
+        return error::invalid("The particle density must not be negative");
+    }
+
+    let effects = &scene.post;
+    if ![effects.blur, effects.bloom, effects.bloom_threshold,
+          effects.sharpen, effects.vignette]
+        .iter().all(|v| *v >= 0.0 && v.is_finite()) {
+        return error::invalid("Post-processing amounts must not be negative");
+    }
+
+    if !(effects.bloom_radius > 0.0 && effects.sharpen_radius > 0.0) {
+        return error::invalid("Bloom and sharpen radii must be positive");
+    }
+
+    if effects.vignette > 1.0 {
+        return error::invalid("Vignette must be between 0 and 1");
+    }
+
+    if !scene.integrator.dt.is_finite() {
+        return error::invalid("The time step must be a finite number");
+    }
+
+    return Ok(());
+}
+
+// Renders a scene to its output file(s), and returns the still image it
+// made (animations and fields don't make one)
+pub fn render(scene: &Scene) -> Result<Option<image::RgbImage>> {
+    // pin down the seed first, so everything below is repeatable and
+    // the recorded scene says exactly how to get this image back
+    let mut scene = scene.clone();
+    let seed = scene.seed.unwrap_or_else(|| u64::from(rand::random::<u32>()));
+    scene.seed = Some(seed);
+    let scene = &scene;
+
+    let out_name = &scene.output.name;
+    let img_width = scene.output.width;
+    let img_height = scene.output.height;
+    let mut rng = _rng(seed, 0);
+
+    validate(scene)?;
+
+    if scene.output.dump_config {
+        scene.save(Path::new(&format!("{}.toml", out_name)))?;
+    }
+
+    match scene.mode.as_str() {
+        "octaves" => {
+            let layers = if scene.noise.layers.is_empty() {
+                default_octaves()
+            } else {
+                scene.noise.layers.clone()
+            };
+
+            let octave_img = make_octaves(&layers, img_width, img_height,
+                                                   scene.noise.tileable,
+                                                   scene.noise.window(),
+                                                   &mut rng)?;
+            let img = gray_image(&octave_img);
+            save_image(image::ImageRgb8(img.clone()),
+                       Path::new(&output_path(scene)),
+                       &make_metadata(scene, None)?)?;
+            return Ok(Some(img));
+        },
+
+        "noise" => {
+            let d1 = make_noise(&scene.noise, img_width, img_height,
+                                &mut rng)?;
+            let img = gray_image(&d1);
+            save_image(image::ImageRgb8(img.clone()),
+                       Path::new(&output_path(scene)),
+                       &make_metadata(scene, None)?)?;
+            return Ok(Some(img));
+        },
+
+        "flow" | "field" => {},
+
+        other => return _unknown("mode", other,
+                                 "flow, noise, octaves or field")
+    }
+    
+    let btwn = Range::new(0.0,
+                          2.0 * std::f64::consts::PI);
+    // always draw the phase, so that fixing it leaves the rest of the
+    // random stream (and so the noise) unchanged
+    let phase = btwn.ind_sample(&mut rng);
+    let phase = scene.field.phase.unwrap_or(phase);
+
+    if scene.mode == "field" && scene.field.save.is_none() {
+        return error::invalid("Nowhere to save the field");
+    }
+
+    let ff = make_field(scene, phase, &mut rng)?;
+    let meta = make_metadata(scene, Some(ff.phase))?;
+
+    if let Some(ref path) = scene.field.save {
+        // a loaded vector field can still be asked for as a PNG
+        field::save(Path::new(path), &ff).map_err(|e| {
+            let what = format!("Could not save the flow field {}", path);
+            match e.kind() {
+                io::ErrorKind::InvalidInput =>
+                    Error::Invalid(format!("{}: {}", what, e)),
+                _ => Error::Io(what, e)
+            }
+        })?;
+    }
+
+    if scene.mode == "field" {
+        return Ok(None);
+    }
+
+    let strategy = seeding_of(scene)?;
+    let seeds = seeding::seed(&strategy, particle_count(scene)?,
+                              img_width, img_height, &mut rng)?;
+
+    // particles that die or leave the canvas come back where density
+    // seeding would have put them
+    let respawn = match strategy {
+        Seeding::Density(ref weights) =>
+            Some(DensitySampler::new(weights)?),
+        _ => None
+    };
+
+    // the mask and color image have to line up with the flux
+    let (trace_width, trace_height) = trace_size(scene);
+    let mask = match scene.particles.mask {
+        Some(ref path) => Some(input::load_grayscale(Path::new(path),
+                                                     trace_width,
+                                                     trace_height)?),
+        None => None
+    };
+
+    let color_image = match scene.color.image {
+        Some(ref path) => Some(input::load_rgb(Path::new(path),
+                                               trace_width, trace_height)?),
+        None => None
+    };
+
+    let steps = scene.integrator.steps;
+    let dt = scene.integrator.dt;
+
+    let mut tracers = make_tracers(scene, seed, &ff, &seeds, mask.as_ref(),
+                                   color_image.as_ref(), respawn.as_ref())?;
+
+
+    // first, progress all the way to the end to get the max flux value
+    tracers.par_iter_mut()
+        .for_each(|t| t.progress_for(steps, dt));
+
+    
+    // next, compute the max flux...
+    let max_flux = total_flux(scene, &tracers)?.max();
+
+    if !scene.animation.enabled {
+        // write the normalized flux out, and we're done.
+        let img = tracers_image(scene, &tracers, max_flux)?;
+        save_image(img.clone(), Path::new(&output_path(scene)), &meta)?;
+        return Ok(Some(img.to_rgb()));
+    }
+
+
+
+    // now, reset / remake all the tracers and generate the frames
+    
+    tracers = make_tracers(scene, seed, &ff, &seeds, mask.as_ref(),
+                           color_image.as_ref(), respawn.as_ref())?;
+
+    
+    let temp_dir = Temp::new_dir()
+        .map_err(error::io("Could not create a directory for the frames"))?;
+
+
+    for frame in 0..steps {
+        if frame % 10 == 0 {
+            println!("Computing frame {} / {}...", frame, steps);
+        }
+        
+        tracers.par_iter_mut()
+            .for_each(|t| t.progress(dt));
+
+        let pb = temp_dir.as_ref()
+            .join(Path::new(&format!("out_{:04}.png",
+                                     frame)
+            ));
+        write_tracers(&pb, scene, &tracers, max_flux, &[])?;
+    }
+
+    // now, run the FFMPEG command
+    // /usr/bin/ffmpeg -framerate 60 -i out_%04d.png -pix_fmt yuv420p out.mp4
+    let ffmpeg = &scene.animation.ffmpeg;
+    let frames = temp_dir.as_ref().join("out_%04d.png");
+    let mut child = Command::new(ffmpeg)
+        .arg("-framerate").arg(scene.animation.framerate.to_string())
+        .arg("-i").arg(&frames)
+        .args(["-pix_fmt", "yuv420p"])
+        .arg(format!("{}.mp4", out_name))
+        .spawn()
+        .map_err(|e| Error::Process(format!("Could not run {}: {}",
+                                            ffmpeg, e)))?;
+    
+    let status = child.wait()
+        .map_err(|e| Error::Process(format!("{} did not finish: {}",
+                                            ffmpeg, e)))?;
+
+    if !status.success() {
+        return Err(Error::Process(format!("{} failed ({})", ffmpeg, status)));
+    }
+
+    return Ok(None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{particle_count, seeding_of, field_size, make_field,
+                make_tracers, total_flux, _rng, with_background,
+                background_of, rgb_image, gray_image, validate,
+                flow_stats_image, FieldColor};
+    use image;
+    use color::{Rgb, Tone};
+    use grid::{Field2D, Lerp};
+    use tracer::FlowStats;
+    use scene::Scene;
+    use seeding;
+
+    fn _canvas_scene(width: usize, height: usize) -> Scene {
+        let mut scene = Scene::default();
+        scene.output.width = width;
+        scene.output.height = height;
+        scene.world.units = String::from("canvas");
+        scene.world.density = 400.0;
+        scene.world.stroke_width = 0.04;
+        scene.field.resolution = Some(45);
+        scene.field.aspect = String::from("canvas");
+        scene.field.interpolate = true;
+        scene.seeding.strategy = String::from("random");
+        scene.integrator.steps = 60;
+        scene.jobs = 1;
+        return scene;
+    }
+
+    // The flux of a scene, traced the way `render` does it
+    fn _flux(scene: &Scene) -> Field2D<f64> {
+        let mut rng = _rng(7, 0);
+        let ff = make_field(scene, 0.0, &mut rng).unwrap();
+        let seeds = seeding::seed(&seeding_of(scene).unwrap(),
+                                  particle_count(scene).unwrap(),
+                                  scene.output.width, scene.output.height,
+                                  &mut rng).unwrap();
+
+        let mut tracers = make_tracers(scene, 7, &ff, &seeds,
+                                       None, None, None).unwrap();
+        for t in &mut tracers {
+            t.progress_for(scene.integrator.steps, scene.integrator.dt);
+        }
+
+        return total_flux(scene, &tracers).unwrap();
+    }
+
+    #[test]
+    fn canvas_units_keep_the_particle_count() {
+        let small = particle_count(&_canvas_scene(480, 270)).unwrap();
+        let large = particle_count(&_canvas_scene(1920, 1080)).unwrap();
+        assert_eq!(small, large);
+
+        let mut pixels = _canvas_scene(480, 270);
+        pixels.world.units = String::from("pixels");
+        assert_eq!(particle_count(&pixels).unwrap(), 480 * 270);
+    }
+
+    #[test]
+    fn canvas_units_keep_flux_and_coverage_across_resolutions() {
+        let small = _flux(&_canvas_scene(160, 90));
+        let large = _flux(&_canvas_scene(480, 270));
+
+        // the same strokes, so the same total weight
+        let total = |f: &Field2D<f64>| f.iter().sum::<f64>();
+        let (ts, tl) = (total(&small), total(&large));
+        assert!((ts - tl).abs() < 0.02 * ts, "{} vs {}", ts, tl);
+
+        // and the same fraction of the canvas drawn on
+        let coverage = |f: &Field2D<f64>| {
+            f.iter().filter(|&&v| v > 0.0).count() as f64
+                / f.data().len() as f64
+        };
+        let (cs, cl) = (coverage(&small), coverage(&large));
+        assert!((cs - cl).abs() < 0.1 * cs, "{} vs {}", cs, cl);
+    }
+
+    #[test]
+    fn zero_particles_are_invalid() {
+        let mut scene = Scene::default();
+        scene.seeding.particles = Some(0);
+        assert!(validate(&scene).is_err());
+
+        scene.seeding.particles = Some(1);
+        assert!(validate(&scene).is_ok());
+    }
+
+    #[test]
+    fn images_are_required_once_the_scene_is_merged() {
+        let mut scene = Scene::default();
+        scene.seeding.strategy = String::from("density");
+        assert!(validate(&scene).is_err());
+        scene.seeding.image = Some(String::from("weights.png"));
+        assert!(validate(&scene).is_ok());
+
+        scene.field.kind = String::from("image-edges");
+        assert!(validate(&scene).is_err());
+        scene.field.image = Some(String::from("edges.png"));
+        assert!(validate(&scene).is_ok());
+
+        // plain noise has no particles or field to feed
+        scene.mode = String::from("noise");
+        scene.seeding.image = None;
+        scene.field.image = None;
+        assert!(validate(&scene).is_ok());
+    }
+
+    #[test]
+    fn heading_colors_saturate_as_the_particles_agree() {
+        // two particles through each pixel: both heading right on the
+        // left, and in opposite directions on the right
+        let flux = Field2D::filled(2, 1, 2.0);
+        let stats = Field2D::from_fn(2, 1, |x, _| FlowStats {
+            speed: 2.0,
+            heading_cos: if x == 0 { 2.0 } else { 0.0 },
+            heading_sin: 0.0,
+            curvature: 0.0
+        });
+
+        let img = flow_stats_image(&flux, &stats, 2.0, 0, &Tone::default(),
+                                   FieldColor::Heading).unwrap();
+        assert_eq!(img.get_pixel(0, 0).data, [255, 0, 0]);
+        assert_eq!(img.get_pixel(1, 0).data, [255, 255, 255]);
+    }
+
+    #[test]
+    fn images_keep_the_grid_orientation() {
+        let ramp = Field2D::from_fn(5, 3, |x, _| x as f64 / 4.0);
+        let img = gray_image(&ramp);
+
+        assert_eq!(img.dimensions(), (5, 3));
+        for y in 0..3 {
+            assert_eq!(img.get_pixel(0, y).data, [0, 0, 0]);
+            assert_eq!(img.get_pixel(4, y).data, [255, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn thin_canvases_still_get_a_field() {
+        let mut scene = Scene::default();
+        scene.output.width = 1;
+        scene.output.height = 40;
+        assert_eq!(field_size(&scene).unwrap(), (1, 1));
+
+        scene.field.aspect = String::from("canvas");
+        assert_eq!(field_size(&scene).unwrap(), (1, 40));
+    }
+
+    #[test]
+    fn huge_fields_are_invalid() {
+        let mut scene = Scene::default();
+        scene.field.resolution = Some(100_000_000);
+        assert!(validate(&scene).is_err());
+
+        scene.field.aspect = String::from("canvas");
+        assert!(validate(&scene).is_err());
+
+        scene.field.resolution = Some(usize::MAX / 2);
+        assert!(validate(&scene).is_err());
+
+        scene.field.resolution = Some(270);
+        assert!(validate(&scene).is_ok());
+    }
+
+    const STROKE: Rgb = (1.0, 0.5, 0.0);
+
+    // A row of strokes covering more and more of each pixel, drawn over
+    // `own` the way the color functions draw them
+    fn _strokes(own: Rgb) -> (image::RgbImage, Field2D<f64>) {
+        let flux = Field2D::from_fn(5, 1, |x, _| x as f64 / 4.0);
+        let img = rgb_image(&flux.map(|&k| Rgb::lerp(own, STROKE, k)));
+        return (img, flux);
+    }
+
+    fn _linear_scene(background: &[&str]) -> Scene {
+        let mut scene = Scene::default();
+        scene.tone.gamma = 1.0;
+        scene.color.background = background.iter()
+            .map(|c| String::from(*c)).collect();
+        return scene;
+    }
+
+    fn _close(a: [u8; 3], b: Rgb) {
+        let b = [b.0 * 255.0, b.1 * 255.0, b.2 * 255.0];
+        for i in 0..3 {
+            assert!((f64::from(a[i]) - b[i]).abs() <= 3.0, "{:?} vs {:?}",
+                    a, b);
+        }
+    }
+
+    #[test]
+    fn no_background_leaves_the_image_alone() {
+        let (img, flux) = _strokes((0.0, 0.0, 0.0));
+        let out = with_background(&_linear_scene(&[]), img.clone(), &flux,
+                                  1.0, Some((0.0, 0.0, 0.0))).unwrap();
+        assert_eq!(out.to_rgb().into_raw(), img.into_raw());
+    }
+
+    #[test]
+    fn backgrounds_replace_the_color_functions_own() {
+        let own = (0.0, 20.0 / 255.0, 0.0);
+        let back = (32.0 / 255.0, 64.0 / 255.0, 128.0 / 255.0);
+        let (img, flux) = _strokes(own);
+
+        let out = with_background(&_linear_scene(&["#204080"]), img, &flux,
+                                  1.0, Some(own)).unwrap().to_rgb();
+        for x in 0..5 {
+            _close(out.get_pixel(x, 0).data,
+                   Rgb::lerp(back, STROKE, flux[(x as usize, 0)]));
+        }
+    }
+
+    #[test]
+    fn transparent_output_keeps_the_strokes_and_their_cover() {
+        let own = (46.0 / 255.0, 59.0 / 255.0, 65.0 / 255.0);
+        let (img, flux) = _strokes(own);
+        let mut scene = _linear_scene(&[]);
+        scene.output.transparent = true;
+
+        let out = with_background(&scene, img, &flux, 1.0, Some(own))
+            .unwrap().to_rgba();
+        assert_eq!(out.get_pixel(0, 0).data, [0, 0, 0, 0]);
+        for x in 1..5 {
+            let px = out.get_pixel(x, 0).data;
+            _close([px[0], px[1], px[2]], STROKE);
+            assert!((f64::from(px[3]) - 255.0 * x as f64 / 4.0).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn gradients_run_from_top_to_bottom() {
+        let flux = Field2D::filled(1, 4, 0.0);
+        let img = image::RgbImage::new(1, 4);
+        let scene = _linear_scene(&["#000000", "#ffffff"]);
+        let out = with_background(&scene, img, &flux, 1.0,
+                                  Some((0.0, 0.0, 0.0))).unwrap().to_rgb();
+
+        let rows: Vec<u8> = (0..4).map(|y| out.get_pixel(0, y).data[0])
+            .collect();
+        assert!(rows.windows(2).all(|w| w[0] < w[1]));
+        assert!(rows[0] < 64 && rows[3] > 191);
+
+        assert!(background_of(&_linear_scene(&["#000", "#fff"])).is_err());
+        assert!(background_of(&_linear_scene(&["#000000", "#ffffff",
+                                               "#808080"])).is_err());
+    }
+}
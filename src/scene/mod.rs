@@ -0,0 +1,498 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use toml;
+use serde_json;
+use color::Tone;
+use perlin::Window;
+use error;
+use error::{Error, Result};
+
+// Everything needed to produce a render. Scenes are read from TOML (or
+// JSON) files, where any missing value takes its default, and can be
+// written back out next to a render to record how it was made.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Scene {
+    // "flow", "noise" (plain Perlin noise), "octaves" or "field" (only
+    // generate and save the flow field)
+    pub mode: String,
+    pub jobs: usize,
+
+    // drives all of the randomness in a render; when missing a random
+    // one is picked and recorded in the output
+    pub seed: Option<u64>,
+    pub output: Output,
+    pub noise: Noise,
+    pub field: FieldSettings,
+    pub seeding: SeedingSettings,
+    pub particles: Particles,
+    pub integrator: Integrator,
+    pub world: World,
+    pub post: Post,
+    pub tone: Tone,
+    pub color: ColorSettings,
+    pub animation: Animation
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Output {
+    // file name without extension
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+
+    // "png", "jpeg", "bmp" or "ppm"
+    pub format: String,
+
+    // the particles are traced at ssaa times the output resolution and
+    // brought back down with the filter: "box", "lanczos" or "mitchell"
+    pub ssaa: usize,
+    pub filter: String,
+
+    // write the effective scene to <name>.toml next to the output
+    pub dump_config: bool,
+
+    // leave the background out: the flux becomes the alpha channel of
+    // an RGBA PNG
+    pub transparent: bool
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Noise {
+    pub frequency: u16,
+    pub tileable: bool,
+
+    // the part of the noise that is shown: the top left corner, and the
+    // length of the shorter side shown (the longer side keeps the
+    // canvas' aspect ratio), in units of the default [0, 1] square.
+    // Offsetting by whole units gives neighboring tiles of a mosaic.
+    pub offset: [f64; 2],
+    pub scale: f64,
+
+    // when non-empty, the noise is a weighted sum of these layers
+    // (renormalized to [0, 1]) instead of a single frequency. TOML
+    // writes tables after plain values, so this stays last.
+    pub layers: Vec<Layer>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Layer {
+    pub frequency: u16,
+    pub weight: f64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FieldSettings {
+    // "angle", "curl", "image-angle", "image-gradient" or "image-edges"
+    pub kind: String,
+    pub image: Option<String>,
+    pub noise_mix: f64,
+
+    // rotation of an angle field in radians; random when missing
+    pub phase: Option<f64>,
+
+    // cells along the shorter side of the canvas, independent of the
+    // output size; half the output's shorter side when missing
+    pub resolution: Option<usize>,
+
+    // "square" stretches one square of noise over the whole canvas,
+    // "canvas" gives the field the canvas' aspect ratio so its cells
+    // stay square
+    pub aspect: String,
+
+    // blend the field between its cells instead of using the nearest
+    pub interpolate: bool,
+    pub load: Option<String>,
+    pub save: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SeedingSettings {
+    // "grid", "random", "jitter", "poisson", "halton", "sobol" or
+    // "density"
+    pub strategy: String,
+
+    // defaults to one particle per output pixel
+    pub particles: Option<usize>,
+
+    // brightness weights for the density strategy
+    pub image: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Particles {
+    // "discard", "wrap", "reflect", "respawn", "emitter" or "clamp"
+    pub boundary: String,
+
+    // x, y and radius of the emitter
+    pub emitter: Vec<f64>,
+    pub lifetime: usize,
+    pub lifetime_spread: f64,
+    pub fade: f64,
+    pub mask: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Integrator {
+    pub dt: f64,
+    pub steps: usize,
+    pub friction: f64
+}
+
+// What the particle count and stroke width are measured against. The
+// time step is always in canvas units.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct World {
+    // "pixels" seeds one particle per output pixel and draws one pixel
+    // per step; "canvas" measures both against the canvas, so a small
+    // preview shows the same image as a large render
+    pub units: String,
+
+    // with canvas units, the particles per square of the canvas'
+    // shorter side (when no count is given) and the width of the
+    // strokes as a fraction of that side
+    pub density: f64,
+    pub stroke_width: f64
+}
+
+// Effects applied to the accumulated image before it's colored. Radii
+// are fractions of the canvas' shorter side, and an effect is off at 0.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Post {
+    // standard deviation of a Gaussian blur
+    pub blur: f64,
+
+    // glow: whatever is brighter than the threshold (a fraction of the
+    // brightest pixel) is blurred and added back this many times
+    pub bloom: f64,
+    pub bloom_threshold: f64,
+    pub bloom_radius: f64,
+
+    // unsharp mask: adds this many times the difference from a blur
+    pub sharpen: f64,
+    pub sharpen_radius: f64,
+
+    // how much darker the corners get
+    pub vignette: f64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ColorSettings {
+    // one of the fixed color functions (0 through 3)
+    pub function: u8,
+
+    // per-stroke colors: "position", "speed", "heading", "age" or
+    // "image" (sampled from `image` at the spot given by `sample`)
+    pub stroke: Option<String>,
+    pub image: Option<String>,
+
+    // "spawn" or "current"
+    pub sample: String,
+
+    // "additive" or "average"
+    pub blend: String,
+
+    // "heading", "speed", "curvature" or "heading-speed"
+    pub field: Option<String>,
+
+    // "#rrggbb" colors the strokes are drawn over: one for a solid
+    // background, two for a gradient from top to bottom. When empty,
+    // each coloring keeps its own background.
+    pub background: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Animation {
+    pub enabled: bool,
+    pub framerate: u32,
+    pub ffmpeg: String
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        return Scene {
+            mode: String::from("flow"),
+            jobs: 1,
+            seed: None,
+            output: Output::default(),
+            noise: Noise::default(),
+            field: FieldSettings::default(),
+            seeding: SeedingSettings::default(),
+            particles: Particles::default(),
+            integrator: Integrator::default(),
+            world: World::default(),
+            post: Post::default(),
+            tone: Tone::default(),
+            color: ColorSettings::default(),
+            animation: Animation::default()
+        };
+    }
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        return Output {
+            name: String::from("out"),
+            width: 960,
+            height: 540,
+            format: String::from("png"),
+            ssaa: 1,
+            filter: String::from("mitchell"),
+            dump_config: false,
+            transparent: false
+        };
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        return Noise {
+            frequency: 3,
+            tileable: false,
+            offset: [0.0, 0.0],
+            scale: 1.0,
+            layers: Vec::new()
+        };
+    }
+}
+
+impl Default for FieldSettings {
+    fn default() -> FieldSettings {
+        return FieldSettings {
+            kind: String::from("angle"),
+            image: None,
+            noise_mix: 0.0,
+            phase: None,
+            resolution: None,
+            aspect: String::from("square"),
+            interpolate: false,
+            load: None,
+            save: None
+        };
+    }
+}
+
+impl Default for SeedingSettings {
+    fn default() -> SeedingSettings {
+        return SeedingSettings {
+            strategy: String::from("grid"),
+            particles: None,
+            image: None
+        };
+    }
+}
+
+impl Default for Particles {
+    fn default() -> Particles {
+        return Particles {
+            boundary: String::from("discard"),
+            emitter: vec![0.5, 0.5, 0.02],
+            lifetime: 0,
+            lifetime_spread: 0.5,
+            fade: 0.2,
+            mask: None
+        };
+    }
+}
+
+impl Default for Integrator {
+    fn default() -> Integrator {
+        return Integrator {
+            dt: 0.0025,
+            steps: 15*60,
+            friction: 0.5
+        };
+    }
+}
+
+impl Default for World {
+    fn default() -> World {
+        return World {
+            units: String::from("pixels"),
+            density: 250000.0,
+            stroke_width: 0.002
+        };
+    }
+}
+
+impl Default for Post {
+    fn default() -> Post {
+        return Post {
+            blur: 0.0,
+            bloom: 0.0,
+            bloom_threshold: 0.5,
+            bloom_radius: 0.01,
+            sharpen: 0.0,
+            sharpen_radius: 0.005,
+            vignette: 0.0
+        };
+    }
+}
+
+impl Default for ColorSettings {
+    fn default() -> ColorSettings {
+        return ColorSettings {
+            function: 0,
+            stroke: None,
+            image: None,
+            sample: String::from("current"),
+            blend: String::from("average"),
+            field: None,
+            background: Vec::new()
+        };
+    }
+}
+
+impl Default for Animation {
+    fn default() -> Animation {
+        return Animation {
+            enabled: false,
+            framerate: 60,
+            ffmpeg: String::from("/usr/bin/ffmpeg")
+        };
+    }
+}
+
+fn _is_json(path: &Path) -> bool {
+    return path.extension()
+        .map(|e| e.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+}
+
+fn _invalid<E: ToString>(what: &str, e: E) -> Error {
+    return Error::Invalid(format!("{}: {}", what, e.to_string()));
+}
+
+impl Noise {
+    pub fn window(&self) -> Window {
+        return Window { x: self.offset[0], y: self.offset[1],
+                        size: self.scale };
+    }
+}
+
+impl Scene {
+    // Reads a scene from a `.json` file, or TOML for anything else
+    pub fn load(path: &Path) -> Result<Scene> {
+        let what = format!("Could not read the scene file {}",
+                           path.display());
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(error::io(what.as_str()))?;
+
+        if _is_json(path) {
+            return serde_json::from_str(&text).map_err(|e| _invalid(&what, e));
+        }
+
+        return toml::from_str(&text).map_err(|e| _invalid(&what, e));
+    }
+
+    pub fn from_toml(text: &str) -> Result<Scene> {
+        return toml::from_str(text)
+            .map_err(|e| _invalid("Not a valid scene", e));
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        // fails for values TOML can't hold, like seeds past 2^63
+        return toml::to_string(self)
+            .map_err(|e| _invalid("Could not write the scene as TOML", e));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = if _is_json(path) {
+            serde_json::to_string_pretty(self)
+                .map_err(|e| _invalid("Could not write the scene as JSON", e))?
+        } else {
+            self.to_toml()?
+        };
+
+        return File::create(path)
+            .and_then(|mut f| f.write_all(text.as_bytes()))
+            .map_err(error::io(format!("Could not write the scene file {}",
+                                       path.display())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use super::{Scene, Layer};
+
+    #[test]
+    fn scenes_with_layers_round_trip_through_toml() {
+        let mut scene = Scene::default();
+        scene.noise.layers = vec![Layer { frequency: 2, weight: 0.5 },
+                                  Layer { frequency: 8, weight: 0.25 }];
+        scene.noise.scale = 2.0;
+
+        let back = Scene::from_toml(&scene.to_toml().unwrap()).unwrap();
+        assert_eq!(back.noise.layers.len(), 2);
+        assert_eq!(back.noise.layers[1].frequency, 8);
+        assert_eq!(back.noise.scale, 2.0);
+    }
+
+    #[test]
+    fn toml_and_json_scenes_load_the_same() {
+        let mut scene = Scene::default();
+        scene.output.width = 64;
+        scene.field.kind = String::from("curl");
+        scene.seeding.particles = Some(500);
+        scene.color.stroke = Some(String::from("heading"));
+        scene.color.background = vec![String::from("#102030")];
+
+        let toml = env::temp_dir().join("perlin-scene-formats.toml");
+        let json = env::temp_dir().join("perlin-scene-formats.json");
+        scene.save(&toml).unwrap();
+        scene.save(&json).unwrap();
+        let from_toml = Scene::load(&toml).unwrap();
+        let from_json = Scene::load(&json).unwrap();
+        fs::remove_file(&toml).unwrap();
+        fs::remove_file(&json).unwrap();
+
+        let text = scene.to_toml().unwrap();
+        assert_eq!(from_toml.to_toml().unwrap(), text);
+        assert_eq!(from_json.to_toml().unwrap(), text);
+    }
+
+    #[test]
+    fn missing_values_take_their_defaults() {
+        let path = env::temp_dir().join("perlin-scene-partial.json");
+        fs::write(&path, r#"{ "output": { "width": 64 } }"#).unwrap();
+        let scene = Scene::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(scene.output.width, 64);
+        assert_eq!(scene.output.height, Scene::default().output.height);
+    }
+}
@@ -0,0 +1,366 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use std::cmp::Ordering;
+use rand::Rng;
+use rand::distributions::{IndependentSample, Range};
+use grid::Field2D;
+use error;
+use error::Result;
+
+pub enum Seeding {
+    // a regular grid, one particle per cell
+    Grid,
+
+    // uniformly random positions
+    Random,
+
+    // a grid with each particle moved randomly inside its cell
+    Jittered,
+
+    // blue noise: no two particles closer than a minimum distance
+    Poisson,
+
+    // low-discrepancy sequences
+    Halton,
+    Sobol,
+
+    // random positions with probability proportional to the weights,
+    // which cover the whole image at any resolution
+    Density(Field2D<f64>)
+}
+
+// Produces `count` particle positions in [0, 1] x [0, 1] for an
+// image of the given size (the grid-based strategies may round the
+// count to fit the aspect ratio)
+pub fn seed<R: Rng>(strategy: &Seeding, count: usize,
+                    img_width: usize, img_height: usize,
+                    rng: &mut R) -> Result<Vec<(f64, f64)>> {
+    return Ok(match *strategy {
+        Seeding::Grid => grid(count, img_width, img_height, false, rng),
+        Seeding::Random => random(count, rng),
+        Seeding::Jittered => grid(count, img_width, img_height, true, rng),
+        Seeding::Poisson => poisson(count, img_width, img_height, rng),
+        Seeding::Halton => halton(count),
+        Seeding::Sobol => sobol(count),
+        Seeding::Density(ref weights) => density(count, weights, rng)?
+    });
+}
+
+fn grid<R: Rng>(count: usize, img_width: usize, img_height: usize,
+                jitter: bool, rng: &mut R) -> Vec<(f64, f64)> {
+    // pick the grid shape so cells are square in image space; with
+    // one particle per pixel this is exactly the pixel grid. Other
+    // counts are rounded to a full grid, so 10 at 16:9 gives 4x3
+    let aspect = img_width as f64 / img_height as f64;
+    let cols = ((count as f64 * aspect).sqrt().round() as usize).max(1);
+    let rows = ((count as f64 / cols as f64).round() as usize).max(1);
+
+    let btwn = Range::new(0.0, 1.0);
+
+    let mut to_r = Vec::new();
+    for x in 0..cols {
+        for y in 0..rows {
+            let (ox, oy) = if jitter {
+                (btwn.ind_sample(rng), btwn.ind_sample(rng))
+            } else {
+                (0.0, 0.0)
+            };
+
+            to_r.push(((x as f64 + ox) / cols as f64,
+                       (y as f64 + oy) / rows as f64));
+        }
+    }
+
+    return to_r;
+}
+
+fn random<R: Rng>(count: usize, rng: &mut R) -> Vec<(f64, f64)> {
+    let btwn = Range::new(0.0, 1.0);
+
+    return (0..count)
+        .map(|_| (btwn.ind_sample(rng), btwn.ind_sample(rng)))
+        .collect();
+}
+
+// Bridson's algorithm, run in pixel space so the minimum distance is
+// the same along both axes
+fn poisson<R: Rng>(count: usize, img_width: usize,
+                   img_height: usize, rng: &mut R) -> Vec<(f64, f64)> {
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let w = img_width as f64;
+    let h = img_height as f64;
+
+    // a maximal Poisson disk set covers about 1.5 r^2 per point, so
+    // aim slightly denser and stop once we have enough
+    let r = (w * h / (count as f64 * 1.6)).sqrt();
+    let cell = r / 2.0_f64.sqrt();
+    let grid_w = (w / cell).ceil() as usize + 1;
+    let grid_h = (h / cell).ceil() as usize + 1;
+
+    let mut grid: Vec<Option<usize>> = vec![None; grid_w * grid_h];
+    let mut points: Vec<(f64, f64)> = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+
+    let first = (rng.gen_range(0.0, w), rng.gen_range(0.0, h));
+    grid[(first.1 / cell) as usize * grid_w + (first.0 / cell) as usize]
+        = Some(0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() && points.len() < count {
+        let a_idx = rng.gen_range(0, active.len());
+        let (px, py) = points[active[a_idx]];
+        let mut found = false;
+
+        for _ in 0..30 {
+            let angle = rng.gen_range(0.0, 2.0 * ::std::f64::consts::PI);
+            let dist = rng.gen_range(r, 2.0 * r);
+            let qx = px + angle.cos() * dist;
+            let qy = py + angle.sin() * dist;
+
+            if qx < 0.0 || qx >= w || qy < 0.0 || qy >= h {
+                continue;
+            }
+
+            let gx = (qx / cell) as usize;
+            let gy = (qy / cell) as usize;
+
+            let mut too_close = false;
+            for ny in gy.saturating_sub(2)..(gy + 3).min(grid_h) {
+                for nx in gx.saturating_sub(2)..(gx + 3).min(grid_w) {
+                    if let Some(n) = grid[ny * grid_w + nx] {
+                        let (ox, oy) = points[n];
+                        if (ox - qx).powi(2) + (oy - qy).powi(2) < r * r {
+                            too_close = true;
+                        }
+                    }
+                }
+            }
+
+            if !too_close {
+                grid[gy * grid_w + gx] = Some(points.len());
+                active.push(points.len());
+                points.push((qx, qy));
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            active.swap_remove(a_idx);
+        }
+    }
+
+    return points.iter()
+        .map(|&(x, y)| (x / w, y / h))
+        .collect();
+}
+
+fn radical_inverse(mut idx: usize, base: usize) -> f64 {
+    let mut to_r = 0.0;
+    let mut f = 1.0 / base as f64;
+
+    while idx > 0 {
+        to_r += f * (idx % base) as f64;
+        idx /= base;
+        f /= base as f64;
+    }
+
+    return to_r;
+}
+
+fn halton(count: usize) -> Vec<(f64, f64)> {
+    // skip index zero, which is the corner (0, 0)
+    return (1..count + 1)
+        .map(|i| (radical_inverse(i, 2), radical_inverse(i, 3)))
+        .collect();
+}
+
+fn sobol(count: usize) -> Vec<(f64, f64)> {
+    // the first two Sobol dimensions, generated in Gray code order:
+    // the first uses v_k = 2^(31-k), the second the primitive
+    // polynomial x + 1, i.e. v_k = v_(k-1) ^ (v_(k-1) >> 1)
+    let mut v1 = [0u32; 32];
+    let mut v2 = [0u32; 32];
+    v1[0] = 1 << 31;
+    v2[0] = 1 << 31;
+    for k in 1..32 {
+        v1[k] = 1 << (31 - k);
+        v2[k] = v2[k - 1] ^ (v2[k - 1] >> 1);
+    }
+
+    let scale = f64::from(1u32 << 31) * 2.0;
+    let mut x = 0u32;
+    let mut y = 0u32;
+    let mut to_r = Vec::new();
+
+    // as with Halton, skip the first point at the origin
+    for i in 1..count + 1 {
+        let c = (!(i - 1)).trailing_zeros() as usize;
+        x ^= v1[c];
+        y ^= v2[c];
+        to_r.push((f64::from(x) / scale, f64::from(y) / scale));
+    }
+
+    return to_r;
+}
+
+fn density<R: Rng>(count: usize, weights: &Field2D<f64>,
+                   rng: &mut R) -> Result<Vec<(f64, f64)>> {
+    let sampler = DensitySampler::new(weights)?;
+    return Ok((0..count).map(|_| sampler.sample(rng)).collect());
+}
+
+// Draws single positions with probability proportional to a grid of
+// weights, from the running sum of the weights. Density seeding uses
+// it for the initial particles and the tracers for respawns, so both
+// follow the same image.
+#[derive(Clone)]
+pub struct DensitySampler {
+    cdf: Vec<f64>,
+    width: usize,
+    height: usize
+}
+
+impl DensitySampler {
+    pub fn new(weights: &Field2D<f64>) -> Result<DensitySampler> {
+        let mut cdf = Vec::with_capacity(weights.data().len());
+        let mut total = 0.0;
+        for w in weights.iter() {
+            total += w.max(0.0);
+            cdf.push(total);
+        }
+
+        if !(total > 0.0 && total.is_finite()) {
+            return error::invalid("The density weights are all zero, so \
+                                   there is nowhere to put particles");
+        }
+
+        return Ok(DensitySampler {
+            cdf,
+            width: weights.width(),
+            height: weights.height()
+        });
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> (f64, f64) {
+        let btwn = Range::new(0.0, 1.0);
+        let total = self.cdf[self.cdf.len() - 1];
+
+        let target = btwn.ind_sample(rng) * total;
+        let idx = match self.cdf.binary_search_by(
+            |v| v.partial_cmp(&target).unwrap_or(Ordering::Less)) {
+            Ok(i) | Err(i) => i.min(self.cdf.len() - 1)
+        };
+
+        // place the particle somewhere inside the chosen pixel
+        let x = (idx % self.width) as f64 + btwn.ind_sample(rng);
+        let y = (idx / self.width) as f64 + btwn.ind_sample(rng);
+        return (x / self.width as f64, y / self.height as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, StdRng};
+    use grid::Field2D;
+    use super::{seed, poisson, radical_inverse, sobol, Seeding};
+
+    fn _rng() -> StdRng {
+        return StdRng::from_seed(&[29]);
+    }
+
+    #[test]
+    fn every_strategy_stays_on_the_canvas() {
+        let weights = Field2D::from_fn(16, 9, |x, y| (x * y) as f64);
+        let strategies = vec![Seeding::Grid, Seeding::Random,
+                              Seeding::Jittered, Seeding::Poisson,
+                              Seeding::Halton, Seeding::Sobol,
+                              Seeding::Density(weights)];
+
+        for strategy in &strategies {
+            let points = seed(strategy, 500, 160, 90, &mut _rng()).unwrap();
+            assert!(!points.is_empty() && points.len() <= 520);
+            assert!(points.iter().all(|&(x, y)| (0.0..=1.0).contains(&x)
+                                      && (0.0..=1.0).contains(&y)));
+        }
+    }
+
+    #[test]
+    fn one_per_pixel_is_the_pixel_grid() {
+        let points = seed(&Seeding::Grid, 16 * 9, 16, 9, &mut _rng())
+            .unwrap();
+        assert_eq!(points.len(), 16 * 9);
+        let on_pixel = |v: f64| (v - v.round()).abs() < 1e-9;
+        assert!(points.iter().all(|&(x, y)| on_pixel(x * 16.0)
+                                  && on_pixel(y * 9.0)));
+    }
+
+    #[test]
+    fn grid_counts_round_to_a_full_grid() {
+        let points = seed(&Seeding::Grid, 10, 16, 9, &mut _rng()).unwrap();
+        assert_eq!(points.len(), 4 * 3);
+    }
+
+    #[test]
+    fn poisson_points_keep_their_distance() {
+        let (w, h, count) = (120.0, 80.0, 300);
+        let r = (w * h / (count as f64 * 1.6)).sqrt();
+        let points = poisson(count, 120, 80, &mut _rng());
+        assert!(points.len() > count / 2);
+
+        for (i, &(ax, ay)) in points.iter().enumerate() {
+            for &(bx, by) in &points[i + 1..] {
+                let d = ((ax - bx) * w).hypot((ay - by) * h);
+                assert!(d >= r - 1e-9, "{} < {}", d, r);
+            }
+        }
+    }
+
+    #[test]
+    fn low_discrepancy_sequences_start_as_expected() {
+        assert_eq!(radical_inverse(1, 2), 0.5);
+        assert_eq!(radical_inverse(6, 2), 0.375);
+        assert!((radical_inverse(5, 3) - 7.0 / 9.0).abs() < 1e-12);
+
+        assert_eq!(sobol(4), vec![(0.5, 0.5), (0.75, 0.25), (0.25, 0.75),
+                                  (0.375, 0.375)]);
+    }
+
+    #[test]
+    fn density_only_seeds_weighted_pixels() {
+        let weights = Field2D::from_fn(8, 4, |x, y| {
+            if (x, y) == (5, 2) { 1.0 } else { 0.0 }
+        });
+        let points = seed(&Seeding::Density(weights), 100, 8, 4,
+                          &mut _rng()).unwrap();
+
+        assert_eq!(points.len(), 100);
+        assert!(points.iter().all(|&(x, y)| {
+            (x * 8.0) as usize == 5 && (y * 4.0) as usize == 2
+        }));
+
+        let empty = Seeding::Density(Field2D::filled(8, 4, 0.0));
+        assert!(seed(&empty, 10, 8, 4, &mut _rng()).is_err());
+    }
+}
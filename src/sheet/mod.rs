@@ -0,0 +1,262 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2018
+// 
+// This file is part of perlin.
+// 
+// perlin is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// perlin is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with perlin.  If not, see <http://www.gnu.org/licenses/>.
+// 
+// < end copyright > 
+use image;
+use image::{GenericImage, RgbImage};
+
+use error;
+use error::Result;
+
+// A render and the lines of text printed under it
+pub struct Tile {
+    pub image: RgbImage,
+    pub caption: Vec<String>
+}
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const ADVANCE: u32 = GLYPH_WIDTH + 1;
+const LINE_HEIGHT: u32 = GLYPH_HEIGHT + 4;
+const PAD: u32 = 8;
+
+const BACKGROUND: [u8; 3] = [24, 24, 24];
+const TEXT: [u8; 3] = [220, 220, 220];
+
+// A 5x7 pixel font, one byte per row with the leftmost pixel in bit 4.
+// Lower case letters are drawn as capitals.
+fn _glyph(c: char) -> [u8; 7] {
+    return match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        ';' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x04, 0x08],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '*' => [0x00, 0x04, 0x15, 0x0E, 0x15, 0x04, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '[' => [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E],
+        ']' => [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '\'' => [0x0C, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04]
+    };
+}
+
+// Draws a line of text with its top left corner at (x, y), clipped to
+// the image
+pub fn draw_text(img: &mut RgbImage, x: u32, y: u32, text: &str,
+                 color: [u8; 3]) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph = _glyph(c);
+        let gx = x + i as u32 * ADVANCE;
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+
+                let (px, py) = (gx + col, y + row as u32);
+                if px < img.width() && py < img.height() {
+                    img.put_pixel(px, py, image::Rgb(color));
+                }
+            }
+        }
+    }
+}
+
+// Cuts text down to what fits in `width` pixels, marking the cut
+fn _fit(text: &str, width: u32) -> String {
+    let max_chars = (width / ADVANCE) as usize;
+    if text.chars().count() <= max_chars {
+        return String::from(text);
+    }
+
+    let mut to_r: String = text.chars()
+        .take(max_chars.saturating_sub(2))
+        .collect();
+    to_r.push_str("..");
+    return to_r;
+}
+
+// Lays the tiles out `cols` to a row, each scaled to `thumb_width`
+// pixels wide with its caption underneath, under an optional title
+pub fn contact_sheet(tiles: &[Tile], cols: usize, thumb_width: u32,
+                     title: Option<&str>) -> Result<RgbImage> {
+    if tiles.is_empty() {
+        return error::invalid("A contact sheet needs at least one image");
+    }
+
+    if thumb_width == 0 {
+        return error::invalid("Thumbnails must be at least 1 pixel wide");
+    }
+
+    let cols = cols.max(1).min(tiles.len());
+    let rows = tiles.len().div_ceil(cols);
+
+    let thumbs: Vec<RgbImage> = tiles.iter()
+        .map(|t| {
+            let (w, h) = t.image.dimensions();
+            if w == thumb_width {
+                return t.image.clone();
+            }
+
+            let th = (thumb_width as f64 * h as f64 / w as f64)
+                .round().max(1.0) as u32;
+            image::imageops::resize(&t.image, thumb_width, th,
+                                    image::FilterType::Triangle)
+        })
+        .collect();
+
+    let thumb_height = thumbs.iter().map(|t| t.height()).max().unwrap();
+    let caption_lines = tiles.iter().map(|t| t.caption.len()).max().unwrap();
+    let cell_width = thumb_width + PAD;
+    let cell_height = thumb_height + caption_lines as u32 * LINE_HEIGHT + PAD;
+    let title_height = if title.is_some() { LINE_HEIGHT + PAD } else { 0 };
+
+    let mut sheet = RgbImage::from_pixel(cols as u32 * cell_width + PAD,
+                                         rows as u32 * cell_height + PAD
+                                         + title_height,
+                                         image::Rgb(BACKGROUND));
+
+    if let Some(title) = title {
+        let width = sheet.width() - 2 * PAD;
+        draw_text(&mut sheet, PAD, PAD, &_fit(title, width), TEXT);
+    }
+
+    for (idx, (tile, thumb)) in tiles.iter().zip(thumbs.iter()).enumerate() {
+        let x = PAD + (idx % cols) as u32 * cell_width;
+        let y = PAD + title_height + (idx / cols) as u32 * cell_height;
+
+        sheet.copy_from(thumb, x, y);
+
+        for (line, text) in tile.caption.iter().enumerate() {
+            draw_text(&mut sheet, x,
+                      y + thumb_height + 3 + line as u32 * LINE_HEIGHT,
+                      &_fit(text, thumb_width), TEXT);
+        }
+    }
+
+    return Ok(sheet);
+}
+
+#[cfg(test)]
+mod tests {
+    use image;
+    use image::RgbImage;
+    use super::{contact_sheet, draw_text, _fit, Tile, PAD, LINE_HEIGHT,
+                ADVANCE, BACKGROUND};
+
+    fn _tile(value: u8, lines: usize) -> Tile {
+        return Tile {
+            image: RgbImage::from_pixel(40, 20, image::Rgb([value; 3])),
+            caption: (0..lines).map(|i| format!("line {}", i)).collect()
+        };
+    }
+
+    #[test]
+    fn tiles_fill_rows_under_the_title() {
+        let tiles: Vec<Tile> = (0..5).map(|i| _tile(50 + i * 40, 2))
+            .collect();
+        let sheet = contact_sheet(&tiles, 3, 40, Some("title")).unwrap();
+
+        let cell_width = 40 + PAD;
+        let cell_height = 20 + 2 * LINE_HEIGHT + PAD;
+        let title_height = LINE_HEIGHT + PAD;
+        assert_eq!(sheet.dimensions(),
+                   (3 * cell_width + PAD, 2 * cell_height + PAD + title_height));
+
+        // the fifth tile is the second of the second row, and the
+        // sixth cell stays empty
+        let (x, y) = (PAD + cell_width, PAD + title_height + cell_height);
+        assert_eq!(sheet.get_pixel(x + 20, y + 10).data, [210; 3]);
+        assert_eq!(sheet.get_pixel(x + cell_width + 20, y + 10).data,
+                   BACKGROUND);
+    }
+
+    #[test]
+    fn thumbnails_keep_their_aspect_ratio() {
+        let sheet = contact_sheet(&[_tile(100, 0)], 4, 80, None).unwrap();
+        assert_eq!(sheet.dimensions(), (80 + 2 * PAD, 40 + 2 * PAD));
+    }
+
+    #[test]
+    fn empty_sheets_are_errors() {
+        assert!(contact_sheet(&[], 2, 40, None).is_err());
+        assert!(contact_sheet(&[_tile(0, 1)], 2, 0, None).is_err());
+    }
+
+    #[test]
+    fn long_captions_are_cut_to_fit() {
+        assert_eq!(_fit("short", 10 * ADVANCE), "short");
+        assert_eq!(_fit("much too long", 6 * ADVANCE), "much..");
+    }
+
+    #[test]
+    fn text_is_clipped_to_the_image() {
+        let mut img = RgbImage::new(8, 8);
+        draw_text(&mut img, 4, 4, "WWW", [255; 3]);
+        assert!(img.pixels().any(|p| p.data == [255; 3]));
+    }
+}
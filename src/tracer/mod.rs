@@ -18,88 +18,656 @@
 // 
 // < end copyright > 
 use std;
-use rand;
+use std::cmp;
+use rand::StdRng;
 use rand::distributions::{IndependentSample, Range};
+use color::{self, Rgb};
+use grid::{Field2D, Weighted};
+use seeding::DensitySampler;
+use error;
+use error::Result;
 
 struct Particle {
     x: f64,
     y: f64,
     vx: f64,
     vy: f64,
+    spawn_x: f64,
+    spawn_y: f64,
+    age: usize,
+    max_age: usize,
+    last_heading: Option<f64>,
     in_bounds: bool
 }
 
+// Per-pixel sums of particle properties, weighted the same way as the
+// flux so that dividing by the flux gives the mean
+#[derive(Clone, Copy, Default)]
+pub struct FlowStats {
+    pub speed: f64,
+    pub heading_cos: f64,
+    pub heading_sin: f64,
+    pub curvature: f64
+}
+
+impl FlowStats {
+    pub fn add(&mut self, other: &FlowStats) {
+        self.add_weighted(other, 1.0);
+    }
+}
+
+impl Weighted for FlowStats {
+    fn zero() -> FlowStats {
+        return FlowStats::default();
+    }
+
+    fn add_weighted(&mut self, other: &FlowStats, w: f64) {
+        self.speed += other.speed * w;
+        self.heading_cos += other.heading_cos * w;
+        self.heading_sin += other.heading_sin * w;
+        self.curvature += other.curvature * w;
+    }
+}
+
+pub enum Field {
+    // values in [0, 1], mapped to a heading angle
+    Angle(Field2D<f64>),
+
+    // divergence-free velocities, see `perlin::curl`
+    Curl(Field2D<(f64, f64)>),
+
+    // arbitrary forces, used as given
+    Vector(Field2D<(f64, f64)>)
+}
+
+impl Field {
+    pub fn width(&self) -> usize {
+        return match *self {
+            Field::Angle(ref f) => f.width(),
+            Field::Curl(ref f) | Field::Vector(ref f) => f.width()
+        };
+    }
+
+    pub fn height(&self) -> usize {
+        return match *self {
+            Field::Angle(ref f) => f.height(),
+            Field::Curl(ref f) | Field::Vector(ref f) => f.height()
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Boundary {
+    // drop particles that leave the canvas
+    Discard,
+
+    // re-enter on the opposite edge (pairs with tileable noise)
+    Wrap,
+
+    // bounce off the edges
+    Reflect,
+
+    // start over at a random location
+    Respawn,
+
+    // start over near a fixed point: (x, y, radius)
+    Emitter(f64, f64, f64),
+
+    // stick to the edge the particle hit
+    Clamp
+}
+
+#[derive(Clone, Copy)]
+pub enum StrokeColor {
+    // hue from the spawn x coordinate, saturation from spawn y
+    Position,
+
+    // slow particles are blue, fast ones red
+    Speed,
+
+    // hue follows the direction of travel
+    Heading,
+
+    // young particles are cool, old ones warm
+    Age,
+
+    // sampled from the color image (see `set_color_image`)
+    Image(ImageSample)
+}
+
+#[derive(Clone, Copy)]
+pub enum ImageSample {
+    // keep the color under the particle's spawn point
+    Spawn,
+
+    // pick up the color under the particle as it moves
+    Current
+}
+
 pub struct Tracer {
-    vec_field: Vec<f64>,
-    flux: Vec<usize>,
-    vec_size: usize,
-    img_width: usize,
-    img_height: usize,
-    particles: Vec<Particle>
-        
+    vec_field: Field2D<(f64, f64)>,
+    flux: Field2D<f64>,
+    particles: Vec<Particle>,
+    boundary: Boundary,
+
+    // maximum particle age in steps (0 for immortal particles) and
+    // the fraction by which each particle's lifetime is randomized
+    lifetime: usize,
+    lifetime_spread: f64,
+
+    // fraction of the lifetime spent fading in (and out)
+    fade: f64,
+
+    // fraction of the velocity lost per unit of time
+    friction: f64,
+
+    // sample the field bilinearly rather than at the nearest cell
+    interpolate: bool,
+
+    // when set, each step is drawn as a line this wide (a fraction of
+    // the canvas' shorter side) instead of a single pixel
+    stroke_width: Option<f64>,
+
+    // per-pixel weights in [0, 1] applied to every flux deposit
+    mask: Option<Field2D<f64>>,
+
+    // when set, respawned particles are placed by this instead of
+    // uniformly, matching density seeding
+    respawn_density: Option<DensitySampler>,
+
+    // when set, each deposit also adds the particle's color here
+    stroke_color: Option<StrokeColor>,
+    color_flux: Field2D<Rgb>,
+    color_image: Option<Field2D<Rgb>>,
+
+    // when set, each deposit also records speed, heading and curvature
+    record_stats: bool,
+    stats: Field2D<FlowStats>,
+
+    // all of the tracer's randomness comes from here, so seeding it
+    // makes a run repeatable
+    rng: StdRng
 }
 
 impl Tracer {
-    pub fn new(vec_field: &[f64],
-               vec_size: usize,
+    // The field covers the whole canvas whatever its resolution; the
+    // flux is collected at img_width x img_height
+    pub fn new(field: &Field,
                img_width: usize, img_height: usize,
-               phase: f64) -> Tracer {
+               phase: f64) -> Result<Tracer> {
+
+        if field.width() == 0 || field.height() == 0
+            || img_width == 0 || img_height == 0 {
+            return error::invalid("Fields and images can't be empty");
+        }
+
+        let vec_field = match *field {
+            Field::Angle(ref angles) => {
+                angles.map(|el| {
+                    let angle = el * 2.0 * std::f64::consts::PI + phase;
+                    (angle.cos(), angle.sin())
+                })
+            },
+
+            // rotating a curl field by the phase would introduce
+            // divergence, so the phase is ignored here
+            Field::Curl(ref vecs) => vecs.clone(),
+
+            Field::Vector(ref vecs) => vecs.clone()
+        };
         
-        let mut to_r =  Tracer {
-            vec_field: Vec::new(),
-            vec_size: vec_size,
-            flux: Vec::new(),
+        let to_r =  Tracer {
+            vec_field,
+            flux: Field2D::filled(img_width, img_height, 0.0),
             particles: Vec::new(),
-            img_width: img_width,
-            img_height: img_height
+            boundary: Boundary::Discard,
+            lifetime: 0,
+            lifetime_spread: 0.0,
+            fade: 0.0,
+            friction: 0.5,
+            interpolate: false,
+            stroke_width: None,
+            mask: None,
+            respawn_density: None,
+            stroke_color: None,
+            color_flux: Field2D::filled(0, 0, (0.0, 0.0, 0.0)),
+            color_image: None,
+            record_stats: false,
+            stats: Field2D::filled(0, 0, FlowStats::default()),
+            rng: StdRng::new()
+                .map_err(error::io("Could not seed the random number \
+                                    generator"))?
         };
 
-        for _ in 0..img_width {
-            for _ in 0..img_height {
-                to_r.flux.push(0);
-            }
+        return Ok(to_r);
+    }
+
+    // Particles added before this are unaffected, so set it first
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    pub fn set_boundary(&mut self, boundary: Boundary) {
+        self.boundary = boundary;
+    }
+
+    pub fn set_lifetime(&mut self, lifetime: usize, spread: f64)
+                        -> Result<()> {
+        if !(0.0..=1.0).contains(&spread) {
+            return error::invalid("Lifetime spread must be between 0 and 1");
         }
 
-        for el in vec_field {
-            to_r.vec_field.push(el * 2.0 * std::f64::consts::PI + phase);
+        self.lifetime = lifetime;
+        self.lifetime_spread = spread;
+
+        let mut parts = std::mem::take(&mut self.particles);
+        for p in &mut parts {
+            p.max_age = self._random_max_age();
+        }
+        self.particles = parts;
+        return Ok(());
+    }
+
+    pub fn set_fade(&mut self, fade: f64) -> Result<()> {
+        if !(0.0..=0.5).contains(&fade) {
+            return error::invalid("Fade must be between 0 and 0.5");
         }
 
-        return to_r;
+        self.fade = fade;
+        return Ok(());
     }
 
-    pub fn add_particle(&mut self, x: f64, y: f64) {
-        assert!(x <= 1.0 && x >= 0.0);
-        assert!(y <= 1.0 && y >= 0.0);
-        
+    pub fn set_friction(&mut self, friction: f64) -> Result<()> {
+        if !(friction >= 0.0 && friction.is_finite()) {
+            return error::invalid("Friction must be a non-negative number");
+        }
+
+        self.friction = friction;
+        return Ok(());
+    }
+
+    pub fn set_interpolate(&mut self, interpolate: bool) {
+        self.interpolate = interpolate;
+    }
+
+    pub fn set_stroke_width(&mut self, width: f64) -> Result<()> {
+        if !(width > 0.0 && width.is_finite()) {
+            return error::invalid("Stroke width must be positive");
+        }
+
+        self.stroke_width = Some(width);
+        return Ok(());
+    }
+
+    fn _matches_flux<T>(&self, grid: &Field2D<T>) -> bool {
+        return grid.width() == self.flux.width()
+            && grid.height() == self.flux.height();
+    }
+
+    pub fn set_mask(&mut self, mask: Field2D<f64>) -> Result<()> {
+        if !self._matches_flux(&mask) {
+            return error::invalid("The mask must match the image size");
+        }
+
+        self.mask = Some(mask);
+        return Ok(());
+    }
+
+    pub fn set_respawn_density(&mut self, sampler: DensitySampler) {
+        self.respawn_density = Some(sampler);
+    }
+
+    pub fn set_stroke_color(&mut self, stroke_color: StrokeColor) {
+        self.stroke_color = Some(stroke_color);
+        self.color_flux = Field2D::filled(self.flux.width(),
+                                          self.flux.height(),
+                                          (0.0, 0.0, 0.0));
+    }
+
+    pub fn set_color_image(&mut self, image: Field2D<Rgb>) -> Result<()> {
+        if !self._matches_flux(&image) {
+            return error::invalid("The color image must match the image \
+                                   size");
+        }
+
+        self.color_image = Some(image);
+        return Ok(());
+    }
+
+    pub fn record_stats(&mut self) {
+        self.record_stats = true;
+        self.stats = Field2D::filled(self.flux.width(), self.flux.height(),
+                                     FlowStats::default());
+    }
+
+    fn _random_max_age(&mut self) -> usize {
+        if self.lifetime == 0 {
+            return 0;
+        }
+
+        if self.lifetime_spread == 0.0 {
+            return self.lifetime;
+        }
+
+        let btwn = Range::new(-self.lifetime_spread, self.lifetime_spread);
+        let age = self.lifetime as f64
+            * (1.0 + btwn.ind_sample(&mut self.rng));
+
+        return cmp::max(age as usize, 1);
+    }
+
+    pub fn add_particle(&mut self, x: f64, y: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+            return error::invalid(format!(
+                "Particle position ({}, {}) is outside the canvas", x, y));
+        }
+
+        let max_age = self._random_max_age();
         self.particles.push(Particle {
-            x: x, y: y,
+            x, y,
             vx: 0.0, vy: 0.0,
+            spawn_x: x, spawn_y: y,
+            age: 0,
+            max_age,
+            last_heading: None,
             in_bounds: true
         });
+
+        return Ok(());
     }
 
-    pub fn add_random_particle(&mut self) {
-        let btwn = Range::new(0.0, 1.0);
-        let mut rng = rand::thread_rng();
-        let x = btwn.ind_sample(&mut rng);
-        let y = btwn.ind_sample(&mut rng);
+    fn _get_accel(&self, x: f64, y: f64) -> (f64, f64) {
+        if self.interpolate {
+            return self.vec_field.sample(x, y);
+        }
 
-        self.add_particle(x, y);
-        
+        return self.vec_field[self.vec_field.cell_at(x, y)];
+    }
+
+    fn _flux_idx(&self, x: f64, y: f64) -> (usize, usize) {
+        return self.flux.cell_at(x, y);
+    }
+
+    fn _inc_flux(&mut self, x: f64, y: f64, weight: f64,
+                 color: Option<Rgb>, stats: Option<FlowStats>) {
+        let idx = self._flux_idx(x, y);
+        self._inc_flux_at(idx, weight, color, stats);
+    }
+
+    // Draws a step from (x0, y0) to (x1, y1) as a line of discs, close
+    // enough together to leave no gaps at any output size. The step's
+    // weight is shared out, so the ink per step doesn't depend on the
+    // resolution either.
+    fn _inc_stroke(&mut self, (x0, y0): (f64, f64), (x1, y1): (f64, f64),
+                   width: f64, weight: f64,
+                   color: Option<Rgb>, stats: Option<FlowStats>) {
+        let img_w = self.flux.width() as f64;
+        let img_h = self.flux.height() as f64;
+        let radius = width * img_w.min(img_h) / 2.0;
+
+        let dx = (x1 - x0) * img_w;
+        let dy = (y1 - y0) * img_h;
+        let spacing = radius.max(1.0) / 2.0;
+        let n = ((dx * dx + dy * dy).sqrt() / spacing).ceil().max(1.0);
+
+        for i in 1..(n as usize + 1) {
+            let t = i as f64 / n;
+            self._inc_disc((x0 + (x1 - x0) * t) * img_w,
+                           (y0 + (y1 - y0) * t) * img_h,
+                           radius, weight / n, color, stats);
+        }
+    }
+
+    // Spreads the weight evenly over the pixels whose centers are
+    // within radius of (px, py), in pixels. Pixels off the canvas keep
+    // their share, so strokes don't get brighter along the edges.
+    fn _inc_disc(&mut self, px: f64, py: f64, radius: f64, weight: f64,
+                 color: Option<Rgb>, stats: Option<FlowStats>) {
+        let x_lo = (px - radius - 0.5).floor() as i64;
+        let x_hi = (px + radius - 0.5).ceil() as i64;
+        let y_lo = (py - radius - 0.5).floor() as i64;
+        let y_hi = (py + radius - 0.5).ceil() as i64;
+        let inside = |cx: i64, cy: i64| {
+            let ddx = cx as f64 + 0.5 - px;
+            let ddy = cy as f64 + 0.5 - py;
+            ddx * ddx + ddy * ddy <= radius * radius
+        };
+
+        let mut count = 0u32;
+        for cy in y_lo..(y_hi + 1) {
+            for cx in x_lo..(x_hi + 1) {
+                if inside(cx, cy) {
+                    count += 1;
+                }
+            }
+        }
+
+        // thinner than a pixel: just the pixel underneath
+        if count == 0 {
+            let idx = self.flux.cell_at(px / self.flux.width() as f64,
+                                        py / self.flux.height() as f64);
+            self._inc_flux_at(idx, weight, color, stats);
+            return;
+        }
+
+        let share = weight / f64::from(count);
+        let w = self.flux.width() as i64;
+        let h = self.flux.height() as i64;
+        for cy in cmp::max(y_lo, 0)..cmp::min(y_hi + 1, h) {
+            for cx in cmp::max(x_lo, 0)..cmp::min(x_hi + 1, w) {
+                if inside(cx, cy) {
+                    self._inc_flux_at((cx as usize, cy as usize), share,
+                                      color, stats);
+                }
+            }
+        }
     }
 
-    fn _get_accel(&self, x: f64, y: f64) -> f64 {
-        let vec_x = (x * self.vec_size as f64) as usize;
-        let vec_y = (y * self.vec_size as f64) as usize;
-        return self.vec_field[vec_y * self.vec_size + vec_x];
+    fn _inc_flux_at(&mut self, idx: (usize, usize), weight: f64,
+                    color: Option<Rgb>, stats: Option<FlowStats>) {
+        let gate = match self.mask {
+            Some(ref mask) => mask[idx],
+            None => 1.0
+        };
+
+        self.flux[idx] += weight * gate;
+
+        if let Some((r, g, b)) = color {
+            let c = &mut self.color_flux[idx];
+            c.0 += r * weight * gate;
+            c.1 += g * weight * gate;
+            c.2 += b * weight * gate;
+        }
+
+        if let Some(st) = stats {
+            self.stats[idx].add_weighted(&st, weight * gate);
+        }
     }
 
-    fn _inc_flux(&mut self, x: f64, y: f64) {
-        let flx_x = (x * self.img_width as f64) as usize;
-        let flx_y = (y * self.img_height as f64) as usize;
+    fn _flow_stats(&self, p: &mut Particle, dt: f64) -> Option<FlowStats> {
+        if !self.record_stats {
+            return None;
+        }
+
+        let speed = (p.vx * p.vx + p.vy * p.vy).sqrt();
+        let heading = p.vy.atan2(p.vx);
+
+        // curvature is the change in heading per unit of distance
+        let curvature = match p.last_heading {
+            Some(last) if speed > 0.0 => {
+                let pi = std::f64::consts::PI;
+                let mut turn = heading - last;
+                while turn > pi { turn -= 2.0 * pi; }
+                while turn < -pi { turn += 2.0 * pi; }
+                turn.abs() / (speed * dt)
+            },
+            _ => 0.0
+        };
+
+        p.last_heading = Some(heading);
 
-        let idx = flx_y * self.img_width + flx_x;
-        self.flux[idx] += 1;
+        return Some(FlowStats {
+            speed,
+            heading_cos: heading.cos(),
+            heading_sin: heading.sin(),
+            curvature
+        });
+    }
+
+    fn _stroke_color(&self, p: &Particle) -> Option<Rgb> {
+        let stroke_color = self.stroke_color?;
+
+        return Some(match stroke_color {
+            StrokeColor::Position => {
+                color::hsv_to_rgb(p.spawn_x, 1.0 - 0.5 * p.spawn_y, 1.0)
+            },
+
+            StrokeColor::Speed => {
+                // with unit forces and the friction in `progress`,
+                // particles top out at a speed of about 1 / friction.
+                // Without friction there's no top speed, so let the
+                // color saturate instead
+                let speed = (p.vx * p.vx + p.vy * p.vy).sqrt();
+                let t = if self.friction > 0.0 {
+                    (speed * self.friction).min(1.0)
+                } else {
+                    1.0 - (-speed).exp()
+                };
+                color::hsv_to_rgb((1.0 - t) * 2.0 / 3.0, 1.0, 1.0)
+            },
+
+            StrokeColor::Heading => {
+                let angle = p.vy.atan2(p.vx);
+                color::hsv_to_rgb(angle / (2.0 * std::f64::consts::PI),
+                                  1.0, 1.0)
+            },
+
+            StrokeColor::Age => {
+                // immortal particles have no lifetime to compare
+                // against, so let their color saturate over time
+                let t = if p.max_age == 0 {
+                    1.0 - (-(p.age as f64) / 300.0).exp()
+                } else {
+                    p.age as f64 / p.max_age as f64
+                };
+
+                color::hsv_to_rgb(0.55 - 0.45 * t, 0.8, 1.0)
+            },
+
+            StrokeColor::Image(sample) => {
+                let img = match self.color_image {
+                    Some(ref img) => img,
+                    None => return None
+                };
+
+                match sample {
+                    ImageSample::Spawn =>
+                        img[self._flux_idx(p.spawn_x, p.spawn_y)],
+                    ImageSample::Current => img[self._flux_idx(p.x, p.y)]
+                }
+            }
+        });
+    }
+
+    fn _handle_boundary(&mut self, p: &mut Particle) {
+        match self.boundary {
+            Boundary::Discard => {
+                p.in_bounds = false;
+            },
+
+            Boundary::Wrap => {
+                p.x -= p.x.floor();
+                p.y -= p.y.floor();
+            },
+
+            Boundary::Reflect => {
+                if p.x < 0.0 || p.x > 1.0 {
+                    p.vx = -p.vx;
+                }
+
+                if p.y < 0.0 || p.y > 1.0 {
+                    p.vy = -p.vy;
+                }
+
+                p.x = Tracer::_reflect(p.x);
+                p.y = Tracer::_reflect(p.y);
+
+                // bouncing off a wall isn't a turn in the flow
+                p.last_heading = None;
+            },
+
+            Boundary::Respawn | Boundary::Emitter(_, _, _) => {
+                self._respawn(p);
+            },
+
+            Boundary::Clamp => {
+                // keep sliding along the edge, but stop moving
+                // outward
+                if p.x < 0.0 || p.x > 1.0 {
+                    p.vx = 0.0;
+                }
+
+                if p.y < 0.0 || p.y > 1.0 {
+                    p.vy = 0.0;
+                }
+
+                p.x = Tracer::_clamp(p.x);
+                p.y = Tracer::_clamp(p.y);
+            }
+        }
+    }
+
+    fn _respawn(&mut self, p: &mut Particle) {
+
+        if let Boundary::Emitter(ex, ey, radius) = self.boundary {
+            let btwn = Range::new(-radius, radius);
+            p.x = Tracer::_clamp(ex + btwn.ind_sample(&mut self.rng));
+            p.y = Tracer::_clamp(ey + btwn.ind_sample(&mut self.rng));
+        } else if let Some(ref density) = self.respawn_density {
+            let (x, y) = density.sample(&mut self.rng);
+            p.x = x;
+            p.y = y;
+        } else {
+            let btwn = Range::new(0.0, 1.0);
+            p.x = btwn.ind_sample(&mut self.rng);
+            p.y = btwn.ind_sample(&mut self.rng);
+        }
+
+        p.vx = 0.0;
+        p.vy = 0.0;
+        p.spawn_x = p.x;
+        p.spawn_y = p.y;
+        p.last_heading = None;
+        p.age = 0;
+        p.max_age = self._random_max_age();
+    }
+
+    fn _age_weight(&self, p: &Particle) -> f64 {
+        if p.max_age == 0 || self.fade <= 0.0 {
+            return 1.0;
+        }
+
+        let t = p.age as f64 / p.max_age as f64;
+        let fade_in = (t / self.fade).min(1.0);
+        let fade_out = ((1.0 - t) / self.fade).min(1.0);
+
+        return fade_in * fade_out;
+    }
+
+    fn _reflect(v: f64) -> f64 {
+        if v < 0.0 {
+            return Tracer::_clamp(-v);
+        }
+
+        if v > 1.0 {
+            return Tracer::_clamp(2.0 - v);
+        }
+
+        return v;
+    }
+
+    fn _clamp(v: f64) -> f64 {
+        return v.clamp(0.0, 1.0);
     }
 
     pub fn progress_for(&mut self, steps: usize, dt: f64) {
@@ -110,62 +678,285 @@ impl Tracer {
 
     pub fn progress(&mut self, dt: f64) {
         // move the particles locally
-        let mut parts = std::mem::replace(&mut self.particles,
-                                          vec![]);
+        let mut parts = std::mem::take(&mut self.particles);
         
-        for mut p in &mut parts {
+        for p in &mut parts {
             // first, figure out what velocity we are
             // currently in (which cell)
-            let angle = self._get_accel(p.x, p.y);
-            let fx = angle.cos();
-            let fy = angle.sin();
+            let (fx, fy) = self._get_accel(p.x, p.y);
+            let from = (p.x, p.y);
 
             
             p.vx += fx * dt;
             p.vy += fy * dt;
 
-            assert!(p.x >= 0.0);
-            assert!(p.y >= 0.0);
-            assert!(p.x <= 1.0);
-            assert!(p.y <= 1.0);
+            debug_assert!(p.x >= 0.0);
+            debug_assert!(p.y >= 0.0);
+            debug_assert!(p.x <= 1.0);
+            debug_assert!(p.y <= 1.0);
             
             p.x += p.vx * dt;
             p.y += p.vy * dt;
 
+            // a particle put back on the canvas may have jumped, so
+            // there's no line to draw from where it was
+            let mut from = Some(from);
             if p.x < 0.0 || p.x > 1.0
                 || p.y < 0.0 || p.y > 1.0 {
-                    p.in_bounds = false;
-                } else {
-                    self._inc_flux(p.x, p.y);
+                    self._handle_boundary(p);
+                    from = None;
+                }
 
-                    let fric = dt * 0.5;
+            if p.in_bounds {
+                p.age += 1;
 
-                    p.vx *= 1.0 - fric;
-                    p.vy *= 1.0 - fric;
+                let weight = self._age_weight(p);
+                let color = self._stroke_color(p);
+                let stats = self._flow_stats(p, dt);
+                match self.stroke_width {
+                    Some(width) => {
+                        let to = (p.x, p.y);
+                        self._inc_stroke(from.unwrap_or(to), to, width,
+                                         weight, color, stats);
+                    },
+                    None => self._inc_flux(p.x, p.y, weight, color, stats)
                 }
 
+                let fric = dt * self.friction;
+
+                p.vx *= 1.0 - fric;
+                p.vy *= 1.0 - fric;
+
+                if p.max_age != 0 && p.age >= p.max_age {
+                    self._respawn(p);
+                }
+            }
+
         }
 
         parts.retain(|p| p.in_bounds);
 
-        std::mem::replace(&mut self.particles, parts);
+        self.particles = parts;
+    }
+
+    pub fn get_unnormalized_flux(&self) -> &Field2D<f64> {
+        return &self.flux;
+    }
+
+    pub fn get_stats(&self) -> Option<&Field2D<FlowStats>> {
+        if !self.record_stats {
+            return None;
+        }
+
+        return Some(&self.stats);
     }
 
-    pub fn get_normalized_flux(&self) -> Vec<f64> {
-        let mut to_r = Vec::new();
+    pub fn get_color_flux(&self) -> Option<&Field2D<Rgb>> {
+        return self.stroke_color.map(|_| &self.color_flux);
+    }
+
+}
 
-        // first, find the largest element in flux
-        let max_val = (*self.flux.iter().max().unwrap()) as f64;
+#[cfg(test)]
+mod tests {
+    use grid::Field2D;
+    use seeding::DensitySampler;
+    use super::{Tracer, Field, Boundary, StrokeColor, ImageSample};
 
-        for val in &self.flux {
-            to_r.push(*val as f64 / max_val);
+    // Each case starts a particle at x = 0.95 moving right through a
+    // uniform rightward field, so one step of 0.1 takes it to x = 1.06
+    // with vx = 1.1, before friction takes off 5%
+    fn _step_across(boundary: Boundary) -> Tracer {
+        let field = Field::Curl(Field2D::filled(4, 4, (1.0, 0.0)));
+        let mut t = Tracer::new(&field, 10, 10, 0.0).unwrap();
+        t.set_boundary(boundary);
+        t.add_particle(0.95, 0.5).unwrap();
+        t.particles[0].vx = 1.0;
+        t.progress(0.1);
+        return t;
+    }
+
+    #[test]
+    fn each_policy_moves_the_particle_back_inside() {
+        // x and vx after the step, or None when the particle is dropped
+        let cases = [(Boundary::Discard, None),
+                     (Boundary::Wrap, Some((0.06, 1.045))),
+                     (Boundary::Reflect, Some((0.94, -1.045))),
+                     (Boundary::Clamp, Some((1.0, 0.0)))];
+
+        for (i, &(boundary, expected)) in cases.iter().enumerate() {
+            let t = _step_across(boundary);
+            let after = t.particles.first().map(|p| (p.x, p.vx));
+            match (after, expected) {
+                (None, None) => {},
+                (Some((x, vx)), Some((ex, evx))) => {
+                    assert!((x - ex).abs() < 1e-9 && (vx - evx).abs() < 1e-9,
+                            "case {}: ({}, {})", i, x, vx);
+                },
+                _ => panic!("case {}: {:?} instead of {:?}",
+                            i, after, expected)
+            }
         }
+    }
 
-        return to_r;
+    #[test]
+    fn respawned_particles_start_over_at_rest() {
+        for _ in 0..20 {
+            let p = &_step_across(Boundary::Respawn).particles[0];
+            assert!((0.0..=1.0).contains(&p.x) && (0.0..=1.0).contains(&p.y));
+            assert!(p.vx == 0.0 && p.vy == 0.0);
+
+            let p = &_step_across(Boundary::Emitter(0.25, 0.75, 0.1))
+                .particles[0];
+            assert!((p.x - 0.25).abs() <= 0.1 && (p.y - 0.75).abs() <= 0.1);
+            assert!(p.vx == 0.0 && p.vy == 0.0);
+        }
     }
 
-    pub fn get_unnormalized_flux(&self) -> &Vec<usize> {
-        return &self.flux;
+    #[test]
+    fn strokes_fade_in_and_out_then_respawn() {
+        // a still field, so the particle keeps depositing on one pixel
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_lifetime(10, 0.0).unwrap();
+        t.set_fade(0.2).unwrap();
+        t.add_particle(0.55, 0.55).unwrap();
+        t.progress_for(10, 0.1);
+
+        // ages 1 to 10 weigh 0.5, seven full steps, 0.5 and 0
+        let total = t.get_unnormalized_flux()[(5, 5)];
+        assert!((total - 8.0).abs() < 1e-9, "{}", total);
+        assert_eq!(t.particles[0].age, 0);
+    }
+
+    #[test]
+    fn masks_gate_deposits_and_density_places_respawns() {
+        // only pixel (2, 7) of the 10 x 10 canvas is lit
+        let lit = Field2D::from_fn(10, 10, |x, y| {
+            if (x, y) == (2, 7) { 1.0 } else { 0.0 }
+        });
+
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_mask(lit.clone()).unwrap();
+        t.set_respawn_density(DensitySampler::new(&lit).unwrap());
+        t.set_lifetime(1, 0.0).unwrap();
+        t.add_particle(0.55, 0.55).unwrap();
+        t.add_particle(0.25, 0.75).unwrap();
+        t.progress(0.1);
+
+        assert_eq!(t.get_unnormalized_flux().iter().sum::<f64>(), 1.0);
+        for p in &t.particles {
+            assert_eq!(((p.x * 10.0) as usize, (p.y * 10.0) as usize),
+                       (2, 7));
+        }
+    }
+
+    #[test]
+    fn stroke_colors_accumulate_alongside_the_flux() {
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_stroke_color(StrokeColor::Position);
+        t.add_particle(0.35, 0.65).unwrap();
+        t.progress_for(3, 0.1);
+
+        // three deposits of the same color on pixel (3, 6)
+        let (r, g, b) = t._stroke_color(&t.particles[0]).unwrap();
+        let (cr, cg, cb) = t.get_color_flux().unwrap()[(3, 6)];
+        assert_eq!(t.get_unnormalized_flux()[(3, 6)], 3.0);
+        assert!((cr - 3.0 * r).abs() < 1e-9 && (cg - 3.0 * g).abs() < 1e-9
+                && (cb - 3.0 * b).abs() < 1e-9);
+    }
+
+    #[test]
+    fn speed_colors_follow_the_friction() {
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_stroke_color(StrokeColor::Speed);
+        t.add_particle(0.5, 0.5).unwrap();
+        t.particles[0].vx = 5.0;
+
+        // halfway to the top speed of 10 is green, and the top speed of
+        // 1 / 0.2 = 5 is red
+        t.set_friction(0.1).unwrap();
+        let (r, g, b) = t._stroke_color(&t.particles[0]).unwrap();
+        assert!(r.abs() < 1e-9 && (g - 1.0).abs() < 1e-9 && b.abs() < 1e-9);
+
+        t.set_friction(0.2).unwrap();
+        assert_eq!(t._stroke_color(&t.particles[0]), Some((1.0, 0.0, 0.0)));
+
+        // at rest is blue, with or without friction
+        t.particles[0].vx = 0.0;
+        t.set_friction(0.0).unwrap();
+        assert_eq!(t._stroke_color(&t.particles[0]), Some((0.0, 0.0, 1.0)));
     }
 
+    // A particle spawned on the red left half of a 10 x 10 image, then
+    // thrown onto the blue right half in one step
+    fn _cross_photo(sample: ImageSample) -> Tracer {
+        let photo = Field2D::from_fn(10, 10, |x, _| {
+            if x < 5 { (1.0, 0.0, 0.0) } else { (0.0, 0.0, 1.0) }
+        });
+
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_stroke_color(StrokeColor::Image(sample));
+        t.set_color_image(photo).unwrap();
+        t.add_particle(0.25, 0.55).unwrap();
+        t.particles[0].vx = 5.0;
+        t.progress(0.1);
+        return t;
+    }
+
+    #[test]
+    fn photo_colors_come_from_under_the_particle() {
+        // the deposit lands on pixel (7, 5)
+        let spawn = _cross_photo(ImageSample::Spawn);
+        assert_eq!(spawn.get_unnormalized_flux()[(7, 5)], 1.0);
+        assert_eq!(spawn.get_color_flux().unwrap()[(7, 5)], (1.0, 0.0, 0.0));
+
+        let current = _cross_photo(ImageSample::Current);
+        assert_eq!(current.get_color_flux().unwrap()[(7, 5)],
+                   (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn straight_flow_records_its_heading_and_no_curvature() {
+        // a uniform field, so the particle heads down and to the right
+        // in a straight line, speeding up as it goes
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (0.6, 0.8))),
+                                20, 20, 0.0).unwrap();
+        t.record_stats();
+        t.add_particle(0.05, 0.05).unwrap();
+        t.progress_for(20, 0.1);
+
+        let flux = t.get_unnormalized_flux();
+        let stats = t.get_stats().unwrap();
+        let mut visited = 0;
+        for (st, &k) in stats.iter().zip(flux.iter()) {
+            if k <= 0.0 {
+                continue;
+            }
+
+            visited += 1;
+            assert!((st.heading_cos / k - 0.6).abs() < 1e-9
+                    && (st.heading_sin / k - 0.8).abs() < 1e-9);
+            assert!(st.curvature.abs() < 1e-9 && st.speed > 0.0);
+        }
+        assert!(visited > 1);
+    }
+
+    #[test]
+    fn reflections_are_not_curvature() {
+        let mut t = Tracer::new(&Field::Curl(Field2D::filled(4, 4, (1.0, 0.0))),
+                                10, 10, 0.0).unwrap();
+        t.set_boundary(Boundary::Reflect);
+        t.record_stats();
+        // one step to get going, then off the right edge
+        t.add_particle(0.85, 0.5).unwrap();
+        t.particles[0].vx = 1.0;
+        t.progress_for(3, 0.1);
+
+        assert!(t.get_stats().unwrap().iter().all(|st| st.curvature == 0.0));
+    }
 }